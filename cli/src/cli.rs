@@ -5,4 +5,6 @@ pub struct CliContext {
     pub program_id: String,
     pub key: StoredKeypair,
     pub indexer_api: String,
+    pub indexer_token: Option<String>,
+    pub indexer_admin_token: Option<String>,
 }
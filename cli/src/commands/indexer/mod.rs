@@ -1,35 +1,239 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
 
 use base64::{engine::general_purpose, Engine as _};
-use borsh::BorshDeserialize;
-use clap::Subcommand;
+use borsh::{BorshDeserialize, BorshSerialize};
+use clap::{Subcommand, ValueEnum};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use veil_types::UTXO;
 
 use crate::cli::CliContext;
 
+/// Number of recent roots per tree the program still accepts proofs against
+pub const ACCEPTED_ROOT_COUNT: usize = darksol::state::ROOT_HISTORY_SIZE as usize;
+
 #[derive(Serialize, Deserialize)]
 pub struct Data {
     pub data: String
 }
 
+/// A root a tree had, as recorded by the indexer
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RootRecord {
+    pub root: String,
+    pub slot: u64,
+    pub leaf_count: u64,
+    /// false while a transaction behind the root may still be rolled back,
+    /// older indexers do not report it
+    #[serde(default)]
+    pub finalized: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RootHistory {
+    pub tree_number: u64,
+    pub roots: Vec<RootRecord>,
+}
+
+/// Position of a note's commitment, as keyed by the indexer
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct NoteKey {
+    pub tree_number: u64,
+    pub leaf_index: u64,
+}
+
+/// Transaction that created or spent a note
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug)]
+pub struct TxRef {
+    pub signature: String,
+    pub slot: u64,
+    /// false while the transaction is only confirmed and may still be rolled back
+    pub finalized: bool,
+    /// unix seconds
+    pub block_time: Option<i64>,
+}
+
+/// How a note reached its owner
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NoteKind {
+    Deposit,
+    /// returned to the sender by a transfer or withdraw
+    Change,
+    /// sent by someone else
+    Incoming,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug)]
+pub struct Note {
+    pub utxo: UTXO,
+    pub nullifier: Vec<u8>,
+    pub kind: NoteKind,
+    pub created_by: TxRef,
+    pub spent: Option<TxRef>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum NoteStatus {
+    Spent,
+    Unspent,
+}
+
+impl NoteStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            NoteStatus::Spent => "spent",
+            NoteStatus::Unspent => "unspent",
+        }
+    }
+}
+
 #[derive(Clone, Debug, Subcommand)]
 pub enum IndexerCommands {
-    GetUtxo {},
+    GetUtxo {
+        /// account registered on the indexer
+        /// if not provided then assume the indexer's default account
+        #[arg(short, long)]
+        account: Option<String>,
+
+        /// only return spent or unspent notes
+        /// if not provided then return all notes
+        #[arg(short, long)]
+        status: Option<NoteStatus>,
+    },
+
+    GetRoot {
+        /// tree number
+        /// if not provided then assume the newest tree
+        #[arg(short, long)]
+        tree_number: Option<u64>,
+    },
+
+    /// List the roots a tree had, newest first
+    GetRootHistory {
+        /// tree number
+        #[arg(short, long)]
+        tree_number: u64,
+
+        /// number of roots to list
+        #[arg(short, long)]
+        limit: Option<usize>,
+    },
+
+    /// Export or import indexer snapshots, requires the admin token
+    Snapshot {
+        #[command(subcommand)]
+        command: SnapshotCommands,
+    },
+}
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum SnapshotCommands {
+    /// Save the indexer's current state to a file
+    Export {
+        /// file to write the snapshot to
+        #[arg(short, long)]
+        output: PathBuf,
 
-    GetRoot {},
+        /// include every account's decrypted notes
+        #[arg(short, long)]
+        notes: bool,
+    },
+
+    /// Replace the indexer's state with a snapshot and resume sync from it
+    Import {
+        /// snapshot file to upload
+        #[arg(short, long)]
+        input: PathBuf,
+    },
+}
+
+/// Fetches the latest `limit` roots of a tree from the indexer, newest first.
+pub async fn fetch_root_history(
+    ctx: &CliContext,
+    tree_number: u64,
+    limit: usize,
+) -> Result<Vec<RootRecord>, String> {
+    let client = Client::new();
+    let response = match client
+        .get(format!("{}/v2/trees/{}/roots", ctx.indexer_api, tree_number))
+        .query(&[("limit", limit)])
+        .send()
+        .await
+    {
+        Ok(resp) => resp,
+        Err(err) => return Err(err.to_string()),
+    };
+
+    if !response.status().is_success() {
+        return Err(format!("indexer returned {}", response.status()));
+    }
+
+    match response.json::<RootHistory>().await {
+        Ok(history) => Ok(history.roots),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Checks the merkle root a proof was generated against is one the program
+/// still accepts. The root has to come with the merkle path the proof used,
+/// any other root of the tree would not verify it.
+pub async fn check_merkle_root(
+    ctx: &CliContext,
+    tree_number: u64,
+    merkle_root: &str,
+) -> Result<Vec<u8>, String> {
+    let roots = fetch_root_history(ctx, tree_number, ACCEPTED_ROOT_COUNT).await?;
+
+    let record = match roots.iter().find(|record| record.root == merkle_root) {
+        Some(record) => record,
+        None => {
+            return Err(format!(
+                "merkle root {} is not among the last {} roots of tree {}, it is stale or unknown",
+                merkle_root, ACCEPTED_ROOT_COUNT, tree_number
+            ));
+        }
+    };
+    if !record.finalized {
+        println!(
+            "merkle root {} is not finalized yet, the transaction fails if it is rolled back",
+            merkle_root
+        );
+    }
+
+    match general_purpose::STANDARD.decode(merkle_root) {
+        Ok(data) => Ok(data),
+        Err(err) => Err(err.to_string()),
+    }
 }
 
 impl IndexerCommands {
     pub async fn handle_command(command: IndexerCommands, ctx: &CliContext) {
         match command {
-            IndexerCommands::GetUtxo {} => {
+            IndexerCommands::GetUtxo { account, status } => {
                 let client = Client::new();
-                let response = match client.get(format!("{}/notes", ctx.indexer_api)).send().await {
+                let mut request = client.get(format!("{}/notes", ctx.indexer_api));
+                if let Some(account) = account {
+                    request = request.query(&[("account", account)]);
+                }
+                if let Some(status) = status {
+                    request = request.query(&[("status", status.as_str())]);
+                }
+                // notes are only served to holders of the account's token
+                if let Some(token) = &ctx.indexer_token {
+                    request = request.bearer_auth(token);
+                }
+
+                let response = match request.send().await {
                     Ok(resp) => resp,
                     Err(err) => return println!("{}", err.to_string()),
                 };
+                if !response.status().is_success() {
+                    let status = response.status();
+                    let message = response.text().await.unwrap_or_default();
+                    return println!("indexer returned {}: {}", status, message);
+                }
 
                 let body = match response.json::<Data>().await {
                     Ok(data) => data,
@@ -40,17 +244,22 @@ impl IndexerCommands {
                     Ok(data) => data,
                     Err(err) => return println!("{}", err.to_string()),
                 }; 
-                let utxos = match HashMap::<u64, UTXO>::try_from_slice(&decode) {
+                let notes = match BTreeMap::<NoteKey, Note>::try_from_slice(&decode) {
                     Ok(data) => data,
                     Err(err) => return println!("{}", err.to_string()),
                 };
 
-                println!("{:#?}", utxos)
+                println!("{:#?}", notes)
             }
 
-            IndexerCommands::GetRoot {} => {
+            IndexerCommands::GetRoot { tree_number } => {
                 let client = Client::new();
-                let response = match client.get(format!("{}/root", ctx.indexer_api)).send().await {
+                let mut request = client.get(format!("{}/root", ctx.indexer_api));
+                if let Some(tree_number) = tree_number {
+                    request = request.query(&[("tree_number", tree_number)]);
+                }
+
+                let response = match request.send().await {
                     Ok(resp) => resp,
                     Err(err) => return println!("{}", err.to_string()),
                 };
@@ -63,6 +272,81 @@ impl IndexerCommands {
                 // return base64 string of the root
                 println!("{:?}", body.data)
             }
+
+            IndexerCommands::GetRootHistory { tree_number, limit } => {
+                let roots = match fetch_root_history(
+                    ctx,
+                    tree_number,
+                    limit.unwrap_or(ACCEPTED_ROOT_COUNT),
+                )
+                .await
+                {
+                    Ok(roots) => roots,
+                    Err(err) => return println!("{}", err),
+                };
+
+                println!("{:#?}", roots)
+            }
+
+            IndexerCommands::Snapshot { command } => match command {
+                SnapshotCommands::Export { output, notes } => {
+                    let client = Client::new();
+                    let mut request = client
+                        .get(format!("{}/admin/snapshot", ctx.indexer_api))
+                        .query(&[("notes", notes)]);
+                    if let Some(token) = &ctx.indexer_admin_token {
+                        request = request.bearer_auth(token);
+                    }
+
+                    let response = match request.send().await {
+                        Ok(resp) => resp,
+                        Err(err) => return println!("{}", err.to_string()),
+                    };
+                    if !response.status().is_success() {
+                        let status = response.status();
+                        let message = response.text().await.unwrap_or_default();
+                        return println!("indexer returned {}: {}", status, message);
+                    }
+
+                    let bytes = match response.bytes().await {
+                        Ok(bytes) => bytes,
+                        Err(err) => return println!("{}", err.to_string()),
+                    };
+                    if let Err(err) = fs::write(&output, &bytes) {
+                        return println!("{}", err.to_string());
+                    }
+
+                    println!("wrote snapshot to {:?}", output)
+                }
+
+                SnapshotCommands::Import { input } => {
+                    let bytes = match fs::read(&input) {
+                        Ok(bytes) => bytes,
+                        Err(err) => return println!("{}", err.to_string()),
+                    };
+
+                    let client = Client::new();
+                    let mut request = client
+                        .post(format!("{}/admin/snapshot", ctx.indexer_api))
+                        .body(bytes);
+                    if let Some(token) = &ctx.indexer_admin_token {
+                        request = request.bearer_auth(token);
+                    }
+
+                    let response = match request.send().await {
+                        Ok(resp) => resp,
+                        Err(err) => return println!("{}", err.to_string()),
+                    };
+                    let status = response.status();
+                    let message = response.text().await.unwrap_or_default();
+                    if !status.is_success() {
+                        return println!("indexer returned {}: {}", status, message);
+                    }
+
+                    // the indexer answers with the cursor it resumes from
+                    println!("{}", message)
+                }
+            },
         }
     }
 }
@@ -1,6 +1,5 @@
 use std::str::FromStr;
 
-use base64::{Engine as _, engine::general_purpose};
 use clap::Subcommand;
 use darksol::derive_pda;
 use solana_client::rpc_response::RpcSimulateTransactionResult;
@@ -12,6 +11,7 @@ use spl_associated_token_account::get_associated_token_address;
 
 use crate::{
     cli::CliContext,
+    commands::indexer::check_merkle_root,
     solana::transaction::{
         create_deposit_instructions_data, create_transfer_instructions_data,
         create_withdraw_instructions_data,
@@ -53,7 +53,8 @@ pub enum TxCommands {
         #[arg(short, long)]
         receiver_viewing_public_key: String,
 
-        // merkle root of the user tree
+        /// merkle root of the user tree the proof was generated against,
+        /// base64 encoded
         #[arg(short, long)]
         merkle_root: String,
 
@@ -86,7 +87,8 @@ pub enum TxCommands {
         #[arg(short, long)]
         receiver_token_account: Option<String>,
 
-        // merkle root of the user tree
+        /// merkle root of the user tree the proof was generated against,
+        /// base64 encoded
         #[arg(short, long)]
         merkle_root: String,
 
@@ -269,8 +271,8 @@ impl TxCommands {
                 let (inputs, outputs) = read_json_file(json_file_path).unwrap();
                 let proof = get_proof_from_file(proof_file_path).unwrap();
 
-                // decode merkle root string
-                let decode = match general_purpose::STANDARD.decode(merkle_root) {
+                // check the merkle root is one the program still accepts
+                let decode = match check_merkle_root(ctx, tree_number, &merkle_root).await {
                     Ok(data) => data,
                     Err(err) => return println!("{}", err.to_string()),
                 };
@@ -385,8 +387,8 @@ impl TxCommands {
                 let (inputs, _outputs) = read_json_file(json_file_path).unwrap();
                 let proof = get_proof_from_file(proof_file_path).unwrap();
 
-                // decode merkle root string
-                let decode = match general_purpose::STANDARD.decode(merkle_root) {
+                // check the merkle root is one the program still accepts
+                let decode = match check_merkle_root(ctx, tree_number, &merkle_root).await {
                     Ok(data) => data,
                     Err(err) => return println!("{}", err.to_string()),
                 };
@@ -16,6 +16,32 @@ pub struct CliConfig {
     pub key: String,
 
     pub rpc_url: String,
+
+    /// rpc urls tried in order when `rpc_url` keeps failing
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rpc_fallback_urls: Vec<String>,
+
+    /// requests per second sent to each rpc url, unlimited when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rpc_rate_limit: Option<u32>,
+
+    /// retries of a failed rpc request, the rpc crate's default when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rpc_max_retries: Option<u32>,
+
+    /// first and longest pause between rpc retries, in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rpc_initial_backoff_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rpc_max_backoff_ms: Option<u64>,
+
+    /// bearer token sent to the indexer for note-bearing endpoints
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub indexer_token: Option<String>,
+
+    /// the indexer's admin token, sent for snapshot export and import
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub indexer_admin_token: Option<String>,
 }
 
 impl CliConfig {
@@ -71,6 +97,13 @@ impl Default for CliConfig {
             key: DEFAULT_KEY.to_string(),
 
             rpc_url: DEFAULT_RPC_URL.to_string(),
+            rpc_fallback_urls: vec![],
+            rpc_rate_limit: None,
+            rpc_max_retries: None,
+            rpc_initial_backoff_ms: None,
+            rpc_max_backoff_ms: None,
+            indexer_token: None,
+            indexer_admin_token: None,
         }
     }
 }
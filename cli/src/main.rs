@@ -14,9 +14,10 @@ use cli::{
     },
     solana::SolanaClient,
 };
-use solana_client::nonblocking::rpc_client::RpcClient;
+use rpc::RpcConfig;
 use solana_sdk::commitment_config::CommitmentConfig;
 use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Parser)]
 #[command(author, version, about)]
@@ -76,11 +77,18 @@ async fn main() {
     let cli = Cli::parse();
     let config = CliConfig::load_or_create(cli.config).unwrap();
 
+    let mut endpoints = vec![cli.rpc_url.unwrap_or(config.rpc_url.clone())];
+    endpoints.extend(config.rpc_fallback_urls.iter().cloned());
+    let rpc_config = RpcConfig::new(endpoints)
+        .with_rate_limit(config.rpc_rate_limit)
+        .with_max_retries(config.rpc_max_retries)
+        .with_backoff(
+            config.rpc_initial_backoff_ms.map(Duration::from_millis),
+            config.rpc_max_backoff_ms.map(Duration::from_millis),
+        );
+
     let solana_client = SolanaClient {
-        client: RpcClient::new_with_commitment(
-            cli.rpc_url.unwrap_or(config.rpc_url),
-            CommitmentConfig::confirmed(),
-        ),
+        client: rpc::new_client(&rpc_config, CommitmentConfig::confirmed()).unwrap(),
         ws_client: None,
     };
 
@@ -101,6 +109,8 @@ async fn main() {
         program_id: cli.program_id,
         key,
         indexer_api: "http://127.0.0.1:3000".to_string(),
+        indexer_token: config.indexer_token.clone(),
+        indexer_admin_token: config.indexer_admin_token.clone(),
     };
 
     match cli.command {
@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions, Permissions};
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+
+use base64::{engine::general_purpose, Engine as _};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use tokio::sync::RwLock;
+
+/// Name of the account registered from the key file at startup.
+pub const DEFAULT_ACCOUNT: &str = "default";
+
+const KEY_LENGTH: usize = 32;
+const TOKEN_LENGTH: usize = 32;
+
+/// Keys the indexer needs to trial-decrypt notes for one account.
+#[derive(Clone, Debug)]
+pub struct AccountKeys {
+    pub viewing_key: Vec<u8>,
+    pub spending_key: Vec<u8>,
+}
+
+impl AccountKeys {
+    pub fn new(viewing_key: Vec<u8>, spending_key: Vec<u8>) -> Result<Self, String> {
+        if viewing_key.len() != KEY_LENGTH || spending_key.len() != KEY_LENGTH {
+            return Err(format!("keys must be {} bytes long", KEY_LENGTH));
+        }
+
+        Ok(AccountKeys {
+            viewing_key,
+            spending_key,
+        })
+    }
+}
+
+/// Random bearer token granting access to one account's notes.
+pub fn generate_token() -> String {
+    let mut token = [0u8; TOKEN_LENGTH];
+    rand::rng().fill_bytes(&mut token);
+
+    general_purpose::URL_SAFE_NO_PAD.encode(token)
+}
+
+fn hash_token(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
+
+/// Whether `token` is `expected`. Their digests are compared so the time it
+/// takes does not depend on how much of the token was right.
+pub fn tokens_match(token: &str, expected: &str) -> bool {
+    hash_token(token) == hash_token(expected)
+}
+
+/// Writes a generated token to `path`, readable by the owner only.
+pub fn write_token_file(path: &Path, token: &str) -> Result<(), String> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|err| format!("cannot open {:?}: {}", path, err))?;
+    // the mode only applies to new files, an existing one may be wider
+    fs::set_permissions(path, Permissions::from_mode(0o600))
+        .map_err(|err| format!("cannot restrict {:?}: {}", path, err))?;
+
+    file.write_all(token.as_bytes())
+        .map_err(|err| format!("cannot write {:?}: {}", path, err))
+}
+
+/// Registered accounts, kept in memory so events never touch the disk.
+pub struct Accounts {
+    keys: RwLock<HashMap<String, AccountKeys>>,
+    // sha256 of each account's access token, the token itself is not kept
+    tokens: RwLock<HashMap<String, Vec<u8>>>,
+}
+
+impl Accounts {
+    pub fn new() -> Self {
+        Accounts {
+            keys: RwLock::new(HashMap::new()),
+            tokens: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Registers an account reachable with `token`, returns false when the
+    /// name is already taken.
+    pub async fn insert(&self, name: String, keys: AccountKeys, token: &str) -> bool {
+        let mut accounts = self.keys.write().await;
+        if accounts.contains_key(&name) {
+            return false;
+        }
+
+        self.tokens.write().await.insert(name.clone(), hash_token(token));
+        accounts.insert(name, keys);
+        true
+    }
+
+    pub async fn remove(&self, name: &str) -> bool {
+        self.tokens.write().await.remove(name);
+        self.keys.write().await.remove(name).is_some()
+    }
+
+    /// Whether `token` grants access to the account's notes. Unknown
+    /// accounts are never authorized.
+    pub async fn authorize(&self, name: &str, token: &str) -> bool {
+        // comparing digests keeps the comparison time independent of the token
+        self.tokens
+            .read()
+            .await
+            .get(name)
+            .is_some_and(|hash| *hash == hash_token(token))
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.keys.read().await.is_empty()
+    }
+
+    pub async fn contains(&self, name: &str) -> bool {
+        self.keys.read().await.contains_key(name)
+    }
+
+    /// Copy of every registered account for one round of trial decryption.
+    pub async fn snapshot(&self) -> Vec<(String, AccountKeys)> {
+        self.keys
+            .read()
+            .await
+            .iter()
+            .map(|(name, keys)| (name.clone(), keys.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> AccountKeys {
+        AccountKeys::new(vec![1; KEY_LENGTH], vec![2; KEY_LENGTH]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn tokens_grant_their_own_account_only() {
+        let accounts = Accounts::new();
+        assert!(accounts.insert("alice".to_string(), keys(), "alice-token").await);
+        assert!(accounts.insert("bob".to_string(), keys(), "bob-token").await);
+        assert!(!accounts.insert("alice".to_string(), keys(), "other").await);
+
+        assert!(accounts.authorize("alice", "alice-token").await);
+        assert!(!accounts.authorize("alice", "bob-token").await);
+        assert!(!accounts.authorize("carol", "alice-token").await);
+
+        assert!(accounts.remove("alice").await);
+        assert!(!accounts.remove("alice").await);
+        assert!(!accounts.authorize("alice", "alice-token").await);
+    }
+
+    #[test]
+    fn rejects_keys_of_the_wrong_length() {
+        assert!(AccountKeys::new(vec![1; 31], vec![2; KEY_LENGTH]).is_err());
+    }
+
+    #[test]
+    fn matches_tokens_by_digest() {
+        assert!(tokens_match("admin", "admin"));
+        assert!(!tokens_match("admin ", "admin"));
+        assert!(!tokens_match("", "admin"));
+    }
+
+    #[test]
+    fn token_file_is_private() {
+        let path = std::env::temp_dir().join(format!("indexer-token-{}", generate_token()));
+        // a leftover file readable by others is tightened
+        fs::write(&path, "old token").unwrap();
+        fs::set_permissions(&path, Permissions::from_mode(0o644)).unwrap();
+
+        write_token_file(&path, "secret").unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        let written = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(written, "secret");
+    }
+}
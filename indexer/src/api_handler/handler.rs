@@ -1,35 +1,338 @@
+use std::collections::BTreeMap;
 use std::sync::Arc;
 
 use axum::Json;
+use axum::body::Bytes;
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode, header::{AUTHORIZATION, CONTENT_TYPE}};
+use axum::response::IntoResponse;
 use base64::{engine::general_purpose, Engine as _};
-use borsh::BorshDeserialize;
-use axum::extract::State;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
 
-use crate::{client::RawData, AppState, Data};
+use crate::{
+    account::{AccountKeys, DEFAULT_ACCOUNT, generate_token, tokens_match},
+    client::{Note, NoteKey},
+    health::Cursor,
+    snapshot,
+    AppState, Data,
+};
 
-fn get_raw_data(data_str: String) -> RawData {
-    let decode = general_purpose::STANDARD.decode(data_str).unwrap();
-    let raw_data = RawData::try_from_slice(&decode).unwrap();
-    raw_data
+#[derive(Deserialize)]
+pub struct RootQuery {
+    /// defaults to the newest tree
+    pub tree_number: Option<u64>,
 }
 
-pub async fn roots(State(state): State<Arc<AppState>>) -> Json<Data> {
-    let state = state.lock().await;
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NoteStatus {
+    Spent,
+    Unspent,
+}
+
+#[derive(Deserialize)]
+pub struct NotesQuery {
+    /// defaults to the account registered from the key file
+    pub account: Option<String>,
+    /// defaults to all notes
+    pub status: Option<NoteStatus>,
+}
+
+#[derive(Deserialize)]
+pub struct RegisterAccount {
+    pub name: String,
+    pub viewing_key: String,  // base64 encode of the viewing key
+    pub spending_key: String, // base64 encode of the spending key
+    /// token the account's notes are served with, generated when not provided
+    pub access_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct SnapshotQuery {
+    /// include every account's decrypted notes, defaults to false
+    pub notes: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct ImportedSnapshot {
+    /// position live sync resumes from
+    pub cursor: Option<Cursor>,
+}
+
+#[derive(Serialize)]
+pub struct RegisteredAccount {
+    pub name: String,
+    pub access_token: String,
+}
+
+#[derive(Serialize)]
+pub struct RemovedAccount {
+    pub name: String,
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
 
-    let raw_data = get_raw_data(state.clone());
-    let root = raw_data.tree_data.root();
+/// Lets a request read an account's notes when it carries the account's
+/// access token or the admin token.
+pub async fn authorize_account(
+    state: &AppState,
+    headers: &HeaderMap,
+    account: &str,
+) -> Result<(), (StatusCode, String)> {
+    let token = bearer_token(headers).ok_or((
+        StatusCode::UNAUTHORIZED,
+        "missing bearer token".to_string(),
+    ))?;
+
+    let is_admin = state
+        .admin_token
+        .as_deref()
+        .is_some_and(|admin_token| tokens_match(token, admin_token));
+    if is_admin || state.accounts.authorize(account, token).await {
+        return Ok(());
+    }
+
+    Err((
+        StatusCode::UNAUTHORIZED,
+        format!("token does not grant access to account {}", account),
+    ))
+}
+
+pub fn authorize_admin(state: &AppState, headers: &HeaderMap) -> Result<(), (StatusCode, String)> {
+    let admin_token = match &state.admin_token {
+        Some(token) => token,
+        None => {
+            return Err((
+                StatusCode::FORBIDDEN,
+                "account management is disabled, no admin token configured".to_string(),
+            ));
+        }
+    };
+
+    match bearer_token(headers) {
+        Some(token) if tokens_match(token, admin_token) => Ok(()),
+        _ => Err((StatusCode::UNAUTHORIZED, "invalid admin token".to_string())),
+    }
+}
+
+pub async fn roots(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<RootQuery>,
+) -> Json<Data> {
+    let db = state.memdb.read().await;
+
+    let tree_number = query.tree_number.or(db.latest_tree_number());
+    let root = tree_number
+        .and_then(|tree_number| db.root(tree_number))
+        .unwrap_or_default();
     let encode =  general_purpose::STANDARD.encode(root);
 
     Json(Data{ data: encode })
 }
 
-pub async fn leafs(State(state): State<Arc<AppState>>) -> Json<Data> {
-    let state = state.lock().await;
+pub async fn leafs(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<NotesQuery>,
+) -> Result<Json<Data>, (StatusCode, String)> {
+    let account = query.account.unwrap_or(DEFAULT_ACCOUNT.to_string());
+    authorize_account(&state, &headers, &account).await?;
+    if !state.accounts.contains(&account).await {
+        return Err((StatusCode::NOT_FOUND, format!("unknown account {}", account)));
+    }
 
-    let raw_data = get_raw_data(state.clone());
-    let utxos = raw_data.utxos_data;
-    let bytes_data = borsh::to_vec(&utxos).unwrap();
+    let db = state.memdb.read().await;
+
+    let notes: BTreeMap<&NoteKey, &Note> = db
+        .notes(&account)
+        .into_iter()
+        .flatten()
+        .filter(|(_, note)| match query.status {
+            Some(status) => note.spent.is_some() == (status == NoteStatus::Spent),
+            None => true,
+        })
+        .collect();
+    let bytes_data = borsh::to_vec(&notes)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
     let encode =  general_purpose::STANDARD.encode(bytes_data);
 
-    Json(Data{ data: encode })
-}
\ No newline at end of file
+    Ok(Json(Data{ data: encode }))
+}
+
+/// Registers an account's keys and replays history to find its notes.
+/// Responds with the token the account's notes are served with.
+pub async fn register_account(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(request): Json<RegisterAccount>,
+) -> Result<Json<RegisteredAccount>, (StatusCode, String)> {
+    authorize_admin(&state, &headers)?;
+
+    let decode_key = |key: &str| {
+        general_purpose::STANDARD
+            .decode(key)
+            .map_err(|err| (StatusCode::BAD_REQUEST, format!("invalid key encoding: {}", err)))
+    };
+    let keys = AccountKeys::new(
+        decode_key(&request.viewing_key)?,
+        decode_key(&request.spending_key)?,
+    )
+    .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+
+    let access_token = request.access_token.unwrap_or_else(generate_token);
+    if !state.accounts.insert(request.name.clone(), keys, &access_token).await {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("account {} already registered", request.name),
+        ));
+    }
+
+    // the new account's notes may predate an imported snapshot. A rescan
+    // already queued picks it up too.
+    state.sync.clear_resume_point();
+    let _ = state.refetch_tx.try_send(());
+
+    Ok(Json(RegisteredAccount {
+        name: request.name,
+        access_token,
+    }))
+}
+
+/// Forgets an account's keys and drops its notes.
+pub async fn remove_account(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+) -> Result<Json<RemovedAccount>, (StatusCode, String)> {
+    authorize_admin(&state, &headers)?;
+
+    if !state.accounts.remove(&name).await {
+        return Err((StatusCode::NOT_FOUND, format!("unknown account {}", name)));
+    }
+
+    state.memdb.write().await.remove_account(&name);
+    state.webhooks.remove(&name).await;
+
+    Ok(Json(RemovedAccount { name }))
+}
+
+/// Serves a snapshot of the current state as a checksummed file.
+pub async fn export_snapshot(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<SnapshotQuery>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    authorize_admin(&state, &headers)?;
+
+    let snapshot = snapshot::take(&state.memdb, &state.sync, query.notes.unwrap_or(false)).await;
+    let bytes = snapshot
+        .encode()
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err))?;
+
+    Ok(([(CONTENT_TYPE, "application/octet-stream")], bytes))
+}
+
+/// Replaces the state with an uploaded snapshot once its roots check out,
+/// against the chain too, then replays the history that landed after it.
+pub async fn import_snapshot(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<ImportedSnapshot>, (StatusCode, String)> {
+    authorize_admin(&state, &headers)?;
+
+    let has_accounts = !state.accounts.is_empty().await;
+    let prepared =
+        snapshot::prepare(&body, has_accounts).map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+
+    // swapped in by the ingestion loop, never in the middle of a transaction
+    let (reply, imported) = oneshot::channel();
+    let stopped = || {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "ingestion loop stopped".to_string(),
+        )
+    };
+    state
+        .imports
+        .send(snapshot::Import { prepared, reply })
+        .await
+        .map_err(|_| stopped())?;
+    let cursor = imported
+        .await
+        .map_err(|_| stopped())?
+        .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+
+    Ok(Json(ImportedSnapshot { cursor }))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn bearer(token: &str) -> HeaderMap {
+        let value = HeaderValue::from_str(&format!("Bearer {}", token)).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, value);
+        headers
+    }
+
+    #[tokio::test]
+    async fn removes_registered_accounts_only() {
+        let state = Arc::new(AppState::for_tests(Some("admin")));
+        let keys = AccountKeys::new(vec![1; 32], vec![2; 32]).unwrap();
+        state.accounts.insert("alice".to_string(), keys, "alice-token").await;
+
+        let remove = |token: &str, name: &str| {
+            remove_account(State(state.clone()), bearer(token), Path(name.to_string()))
+        };
+
+        let denied = remove("alice-token", "alice").await.err().map(|(status, _)| status);
+        assert_eq!(denied, Some(StatusCode::UNAUTHORIZED));
+        let unknown = remove("admin", "bob").await.err().map(|(status, _)| status);
+        assert_eq!(unknown, Some(StatusCode::NOT_FOUND));
+
+        let removed = remove("admin", "alice").await.ok().map(|Json(removed)| removed.name);
+        assert_eq!(removed.as_deref(), Some("alice"));
+        assert!(!state.accounts.contains("alice").await);
+    }
+
+    #[tokio::test]
+    async fn handlers_read_alongside_other_readers() {
+        let state = Arc::new(AppState::for_tests(None));
+        state.memdb.write().await.insert(0, 0, vec![vec![1; 32]], 10).unwrap();
+        let expected = general_purpose::STANDARD.encode(state.memdb.read().await.root(0).unwrap());
+
+        // a reader holding the lock does not keep handlers waiting
+        let held = state.memdb.read().await;
+        let query = RootQuery { tree_number: None };
+        let served = tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            roots(State(state.clone()), Query(query)),
+        )
+        .await;
+        drop(held);
+
+        assert_eq!(served.ok().map(|Json(root)| root.data), Some(expected));
+    }
+
+    #[test]
+    fn only_the_admin_token_manages_accounts() {
+        let state = AppState::for_tests(Some("admin"));
+        assert!(authorize_admin(&state, &bearer("admin")).is_ok());
+        let denied = authorize_admin(&state, &bearer("admim")).err().map(|(status, _)| status);
+        assert_eq!(denied, Some(StatusCode::UNAUTHORIZED));
+
+        let disabled = AppState::for_tests(None);
+        let denied = authorize_admin(&disabled, &bearer("admin")).err().map(|(status, _)| status);
+        assert_eq!(denied, Some(StatusCode::FORBIDDEN));
+    }
+}
@@ -1 +1,4 @@
 pub mod handler;
+pub mod status;
+pub mod v2;
+pub mod webhook;
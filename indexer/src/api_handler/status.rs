@@ -0,0 +1,157 @@
+use std::sync::Arc;
+
+use axum::Json;
+use axum::extract::State;
+use axum::http::{StatusCode, header::CONTENT_TYPE};
+use axum::response::IntoResponse;
+use prometheus::TEXT_FORMAT;
+use serde::Serialize;
+
+use crate::{
+    AppState,
+    health::{Cursor, LastError},
+};
+
+// slots the ingestion loop may trail the chain tip and still be ready
+pub const MAX_READY_LAG: i64 = 150;
+
+#[derive(Serialize)]
+pub struct TreeStatus {
+    pub tree_number: u64,
+    pub leaf_count: u64,
+}
+
+#[derive(Serialize)]
+pub struct SyncDetail {
+    pub cursor: Option<Cursor>,
+    pub trees: Vec<TreeStatus>,
+    pub last_error: Option<LastError>,
+}
+
+#[derive(Serialize)]
+pub struct HealthResponse {
+    pub status: &'static str,
+    #[serde(flatten)]
+    pub detail: SyncDetail,
+}
+
+#[derive(Serialize)]
+pub struct ReadyResponse {
+    pub ready: bool,
+    pub backfill_complete: bool,
+    pub websocket_connected: bool,
+    pub lag: i64,
+    pub max_lag: i64,
+    // trees holding back leaves until the ones in front of them arrive
+    pub trees_with_gaps: Vec<u64>,
+    #[serde(flatten)]
+    pub detail: SyncDetail,
+}
+
+async fn sync_detail(state: &AppState) -> SyncDetail {
+    let trees = state
+        .memdb
+        .read()
+        .await
+        .roots()
+        .into_iter()
+        .map(|(tree_number, _, leaf_count)| TreeStatus {
+            tree_number,
+            leaf_count,
+        })
+        .collect();
+
+    SyncDetail {
+        cursor: state.sync.cursor(),
+        trees,
+        last_error: state.sync.last_error(),
+    }
+}
+
+/// Liveness: answers as long as the process serves requests.
+pub async fn health(State(state): State<Arc<AppState>>) -> Json<HealthResponse> {
+    Json(HealthResponse {
+        status: "ok",
+        detail: sync_detail(&state).await,
+    })
+}
+
+/// Readiness: the backfill finished, the log subscription is up, no tree
+/// waits for missing leaves and, while transactions wait to be processed, the
+/// index trails the chain tip by at most `MAX_READY_LAG` slots.
+pub async fn ready(State(state): State<Arc<AppState>>) -> (StatusCode, Json<ReadyResponse>) {
+    let backfill_complete = state.sync.backfill_complete();
+    let websocket_connected = state.sync.websocket_connected();
+    let lag = state.metrics.slot_lag.get();
+    // the lag means nothing until the chain tip was sampled once
+    let tip_known = state.metrics.chain_tip_slot.get() > 0;
+    let trees_with_gaps = state.memdb.read().await.trees_with_gaps();
+    let ready = backfill_complete
+        && websocket_connected
+        && tip_known
+        && lag <= MAX_READY_LAG
+        && trees_with_gaps.is_empty();
+
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (
+        status,
+        Json(ReadyResponse {
+            ready,
+            backfill_complete,
+            websocket_connected,
+            lag,
+            max_lag: MAX_READY_LAG,
+            trees_with_gaps,
+            detail: sync_detail(&state).await,
+        }),
+    )
+}
+
+/// Prometheus scrape endpoint.
+pub async fn metrics(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    // tree sizes are read at scrape time so rollbacks are reflected too
+    state.metrics.set_tree_sizes(&state.memdb.read().await.roots());
+
+    match state.metrics.encode() {
+        Ok(body) => (StatusCode::OK, [(CONTENT_TYPE, TEXT_FORMAT)], body).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn ready_once_synced_and_without_gaps() {
+        let state = Arc::new(AppState::for_tests(None));
+
+        let (status, Json(response)) = ready(State(state.clone())).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(!response.backfill_complete);
+
+        state.sync.set_backfill_complete();
+        state.sync.set_websocket_connected(true);
+        state.metrics.chain_tip(1_000);
+        state.metrics.processed_slot(1_000 - MAX_READY_LAG as u64);
+        let (status, _) = ready(State(state.clone())).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // trailing the tip by more than allowed
+        state.metrics.chain_tip(1_001);
+        let (status, Json(response)) = ready(State(state.clone())).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.lag, MAX_READY_LAG + 1);
+
+        state.metrics.processed_slot(1_001);
+        state.memdb.write().await.insert(0, 2, vec![vec![1; 32]], 1_001).unwrap();
+        let (status, Json(response)) = ready(State(state)).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.trees_with_gaps, vec![0]);
+    }
+}
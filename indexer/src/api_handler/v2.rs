@@ -0,0 +1,695 @@
+use std::convert::Infallible;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use axum::Json;
+use axum::extract::rejection::QueryRejection;
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use base64::{engine::general_purpose, Engine as _};
+use futures::{Stream, StreamExt, stream};
+use serde::{Deserialize, Serialize};
+use solana_sdk::pubkey::Pubkey;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    api_handler::handler::{NoteStatus, authorize_account},
+    client::{Note, NoteKey, RootRecord, TxRef},
+    AppState,
+};
+
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+
+/// Error returned by every v2 endpoint, serialized as
+/// `{"error": {"code": .., "message": ..}}`.
+pub struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    pub fn bad_request(message: String) -> Self {
+        ApiError { status: StatusCode::BAD_REQUEST, code: "bad_request", message }
+    }
+
+    pub fn unauthorized(message: String) -> Self {
+        ApiError { status: StatusCode::UNAUTHORIZED, code: "unauthorized", message }
+    }
+
+    pub fn forbidden(message: String) -> Self {
+        ApiError { status: StatusCode::FORBIDDEN, code: "forbidden", message }
+    }
+
+    pub fn not_found(message: String) -> Self {
+        ApiError { status: StatusCode::NOT_FOUND, code: "not_found", message }
+    }
+
+    pub fn gone(message: String) -> Self {
+        ApiError { status: StatusCode::GONE, code: "gone", message }
+    }
+
+    pub fn internal(message: String) -> Self {
+        ApiError { status: StatusCode::INTERNAL_SERVER_ERROR, code: "internal", message }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: ErrorDetail,
+}
+
+#[derive(Serialize)]
+struct ErrorDetail {
+    code: &'static str,
+    message: String,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            error: ErrorDetail {
+                code: self.code,
+                message: self.message,
+            },
+        };
+
+        (self.status, Json(body)).into_response()
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        ApiError::bad_request(rejection.body_text())
+    }
+}
+
+#[derive(Serialize)]
+pub struct RootView {
+    pub tree_number: u64,
+    pub root: String, // base64 encode of the root
+    pub leaf_count: u64,
+}
+
+impl RootView {
+    pub fn new(tree_number: u64, root: &[u8], leaf_count: u64) -> Self {
+        RootView {
+            tree_number,
+            root: general_purpose::STANDARD.encode(root),
+            leaf_count,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct RootsResponse {
+    pub roots: Vec<RootView>,
+}
+
+#[derive(Serialize)]
+pub struct TxView {
+    pub signature: String,
+    pub slot: u64,
+    pub finalized: bool,
+    pub block_time: Option<i64>, // unix seconds
+}
+
+impl From<&TxRef> for TxView {
+    fn from(tx: &TxRef) -> Self {
+        TxView {
+            signature: tx.signature.clone(),
+            slot: tx.slot,
+            finalized: tx.finalized,
+            block_time: tx.block_time,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct NoteView {
+    pub tree_number: u64,
+    pub leaf_index: u64,
+    pub commitment: String, // base64 encode of the leaf
+    pub token: String,      // token mint address
+    pub amount: u64,
+    pub memo: String,
+    pub kind: &'static str, // deposit, change or incoming
+    pub spent: bool,
+    pub created_by: TxView,
+    pub spent_by: Option<TxView>,
+}
+
+impl NoteView {
+    pub fn new(key: &NoteKey, note: &Note) -> Self {
+        NoteView {
+            tree_number: key.tree_number,
+            leaf_index: key.leaf_index,
+            commitment: general_purpose::STANDARD.encode(note.utxo.utxo_hash()),
+            token: token_address(&note.utxo.token_id()),
+            amount: note.utxo.amount(),
+            memo: note.utxo.memo(),
+            kind: note.kind.as_str(),
+            spent: note.spent.is_some(),
+            created_by: TxView::from(&note.created_by),
+            spent_by: note.spent.as_ref().map(TxView::from),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct NotesResponse {
+    pub notes: Vec<NoteView>,
+    /// pass back as `cursor` to get the next page, absent on the last page
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct NotesQuery {
+    /// token mint address
+    pub token: Option<String>,
+    pub status: Option<NoteStatus>,
+    pub cursor: Option<String>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct EventsQuery {
+    /// resume after this sequence number, `Last-Event-ID` works too
+    pub since: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct LeavesQuery {
+    /// first leaf index to return, defaults to 0
+    pub from: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+/// Authentication path of the newest leaf. Together with every leaf it lets a
+/// client rebuild the root and keep appending on its own.
+#[derive(Serialize)]
+pub struct Frontier {
+    pub leaf_index: u64,
+    pub siblings: Vec<String>, // base64 encode of each sibling, leaf level first
+}
+
+#[derive(Serialize)]
+pub struct LeavesResponse {
+    pub tree_number: u64,
+    pub from: u64,
+    pub leaves: Vec<String>, // base64 encode of each commitment
+    pub next_leaf_index: u64,
+    pub leaf_count: u64,
+    pub root: String,
+    /// only present on the page that reaches the newest leaf
+    pub frontier: Option<Frontier>,
+}
+
+/// Authentication path of a single leaf against the tree's current root.
+#[derive(Serialize)]
+pub struct MerklePathResponse {
+    pub tree_number: u64,
+    pub leaf_index: u64,
+    pub leaf: String,          // base64 encode of the commitment
+    pub siblings: Vec<String>, // base64 encode of each sibling, leaf level first
+    pub root: String,
+    pub leaf_count: u64,
+}
+
+#[derive(Deserialize)]
+pub struct NullifiersRequest {
+    /// base64 encode of each nullifier to look up
+    pub nullifiers: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct NullifierView {
+    pub nullifier: String, // as sent
+    pub spent: bool,
+    pub spent_by: Option<TxView>,
+}
+
+#[derive(Serialize)]
+pub struct NullifiersResponse {
+    /// in the order of the request
+    pub nullifiers: Vec<NullifierView>,
+}
+
+#[derive(Deserialize)]
+pub struct RootHistoryQuery {
+    /// base64 encode of a root to look up instead of listing
+    pub root: Option<String>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct RootRecordView {
+    pub root: String, // base64 encode of the root
+    pub slot: u64,
+    pub leaf_count: u64,
+    // false while a transaction behind the root may still be rolled back
+    pub finalized: bool,
+}
+
+impl RootRecordView {
+    pub fn new(record: &RootRecord, finalized_leaf_count: u64) -> Self {
+        RootRecordView {
+            root: general_purpose::STANDARD.encode(&record.root),
+            slot: record.slot,
+            leaf_count: record.leaf_count,
+            finalized: record.leaf_count <= finalized_leaf_count,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct RootHistoryResponse {
+    pub tree_number: u64,
+    /// newest first
+    pub roots: Vec<RootRecordView>,
+}
+
+// mints are shown base58 like everywhere else on solana, anything that is not
+// a pubkey falls back to base64
+fn token_address(token_id: &[u8]) -> String {
+    match Pubkey::try_from(token_id) {
+        Ok(pubkey) => pubkey.to_string(),
+        Err(_) => general_purpose::STANDARD.encode(token_id),
+    }
+}
+
+// cursors are the base64 encode of the last returned "tree:leaf"
+fn encode_cursor(key: &NoteKey) -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(format!("{}:{}", key.tree_number, key.leaf_index))
+}
+
+fn decode_cursor(cursor: &str) -> Result<NoteKey, ApiError> {
+    let invalid = || ApiError::bad_request("invalid cursor".to_string());
+
+    let decoded = general_purpose::URL_SAFE_NO_PAD.decode(cursor).map_err(|_| invalid())?;
+    let decoded = String::from_utf8(decoded).map_err(|_| invalid())?;
+    let (tree_number, leaf_index) = decoded.split_once(':').ok_or_else(invalid)?;
+
+    Ok(NoteKey {
+        tree_number: tree_number.parse().map_err(|_| invalid())?,
+        leaf_index: leaf_index.parse().map_err(|_| invalid())?,
+    })
+}
+
+pub async fn roots(State(state): State<Arc<AppState>>) -> Result<Json<RootsResponse>, ApiError> {
+    let db = state.memdb.read().await;
+
+    let roots = db
+        .roots()
+        .into_iter()
+        .map(|(tree_number, root, leaf_count)| RootView::new(tree_number, &root, leaf_count))
+        .collect();
+
+    Ok(Json(RootsResponse { roots }))
+}
+
+/// Roots a tree had, newest first, or the record of a single root when
+/// `root` is given.
+pub async fn root_history(
+    State(state): State<Arc<AppState>>,
+    Path(tree_number): Path<u64>,
+    query: Result<Query<RootHistoryQuery>, QueryRejection>,
+) -> Result<Json<RootHistoryResponse>, ApiError> {
+    let Query(query) = query?;
+    let root = match &query.root {
+        Some(root) => Some(
+            general_purpose::STANDARD
+                .decode(root)
+                .map_err(|err| ApiError::bad_request(format!("invalid root: {}", err)))?,
+        ),
+        None => None,
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    let db = state.memdb.read().await;
+    let tree = db
+        .tree(tree_number)
+        .ok_or_else(|| ApiError::not_found(format!("unknown tree {}", tree_number)))?;
+
+    let finalized_leaf_count = db.finalized_leaf_count(tree_number);
+    let view = |record: &RootRecord| RootRecordView::new(record, finalized_leaf_count);
+    let roots: Vec<RootRecordView> = match root {
+        Some(root) => tree
+            .root_history()
+            .filter(|record| record.root == root)
+            .take(1)
+            .map(view)
+            .collect(),
+        None => tree.root_history().take(limit).map(view).collect(),
+    };
+
+    if query.root.is_some() && roots.is_empty() {
+        return Err(ApiError::not_found(format!(
+            "root was never produced by tree {}",
+            tree_number
+        )));
+    }
+
+    Ok(Json(RootHistoryResponse { tree_number, roots }))
+}
+
+/// Commitments appended to a tree since `from`, for clients keeping their own
+/// copy of the tree. Nothing about the caller's notes is revealed.
+pub async fn leaves(
+    State(state): State<Arc<AppState>>,
+    Path(tree_number): Path<u64>,
+    query: Result<Query<LeavesQuery>, QueryRejection>,
+) -> Result<Json<LeavesResponse>, ApiError> {
+    let Query(query) = query?;
+    let from = query.from.unwrap_or(0);
+    let limit = query.limit.unwrap_or(MAX_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    let db = state.memdb.read().await;
+    let tree = db
+        .tree(tree_number)
+        .ok_or_else(|| ApiError::not_found(format!("unknown tree {}", tree_number)))?;
+
+    let leaf_count = tree.next_leaf_index();
+    if from > leaf_count {
+        return Err(ApiError::bad_request(format!(
+            "tree {} only has {} leaves",
+            tree_number, leaf_count
+        )));
+    }
+
+    let leaves = tree.leaves(from, limit);
+    let next_leaf_index = from + leaves.len() as u64;
+    let frontier = if next_leaf_index == leaf_count && leaf_count > 0 {
+        tree.merkle_path(leaf_count - 1).map(|siblings| Frontier {
+            leaf_index: leaf_count - 1,
+            siblings: siblings
+                .iter()
+                .map(|sibling| general_purpose::STANDARD.encode(sibling))
+                .collect(),
+        })
+    } else {
+        None
+    };
+
+    Ok(Json(LeavesResponse {
+        tree_number,
+        from,
+        leaves: leaves
+            .iter()
+            .map(|leaf| general_purpose::STANDARD.encode(leaf))
+            .collect(),
+        next_leaf_index,
+        leaf_count,
+        root: general_purpose::STANDARD.encode(tree.root()),
+        frontier,
+    }))
+}
+
+/// Path from a leaf to the current root, so a client can prove membership
+/// of its note without keeping the tree.
+pub async fn merkle_path(
+    State(state): State<Arc<AppState>>,
+    Path((tree_number, leaf_index)): Path<(u64, u64)>,
+) -> Result<Json<MerklePathResponse>, ApiError> {
+    let db = state.memdb.read().await;
+    let tree = db
+        .tree(tree_number)
+        .ok_or_else(|| ApiError::not_found(format!("unknown tree {}", tree_number)))?;
+
+    let leaf_count = tree.next_leaf_index();
+    let (leaf, siblings) = match (tree.leaves(leaf_index, 1).pop(), tree.merkle_path(leaf_index)) {
+        (Some(leaf), Some(siblings)) => (leaf, siblings),
+        _ => {
+            return Err(ApiError::not_found(format!(
+                "tree {} only has {} leaves",
+                tree_number, leaf_count
+            )));
+        }
+    };
+
+    Ok(Json(MerklePathResponse {
+        tree_number,
+        leaf_index,
+        leaf: general_purpose::STANDARD.encode(leaf),
+        siblings: siblings
+            .iter()
+            .map(|sibling| general_purpose::STANDARD.encode(sibling))
+            .collect(),
+        root: general_purpose::STANDARD.encode(tree.root()),
+        leaf_count,
+    }))
+}
+
+/// Whether each nullifier was revealed on chain and by which transaction.
+/// Taken as a POST body so the nullifiers stay out of access logs.
+pub async fn nullifiers(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<NullifiersRequest>,
+) -> Result<Json<NullifiersResponse>, ApiError> {
+    if request.nullifiers.len() > MAX_PAGE_SIZE {
+        return Err(ApiError::bad_request(format!(
+            "at most {} nullifiers per request",
+            MAX_PAGE_SIZE
+        )));
+    }
+
+    let decoded = request
+        .nullifiers
+        .iter()
+        .map(|nullifier| {
+            general_purpose::STANDARD
+                .decode(nullifier)
+                .map_err(|err| ApiError::bad_request(format!("invalid nullifier {}: {}", nullifier, err)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let db = state.memdb.read().await;
+    let nullifiers = request
+        .nullifiers
+        .into_iter()
+        .zip(decoded)
+        .map(|(nullifier, bytes)| {
+            let spent_by = db.nullifier(&bytes);
+            NullifierView {
+                nullifier,
+                spent: spent_by.is_some(),
+                spent_by: spent_by.map(TxView::from),
+            }
+        })
+        .collect();
+
+    Ok(Json(NullifiersResponse { nullifiers }))
+}
+
+pub async fn notes(
+    State(state): State<Arc<AppState>>,
+    Path(account): Path<String>,
+    headers: HeaderMap,
+    query: Result<Query<NotesQuery>, QueryRejection>,
+) -> Result<Json<NotesResponse>, ApiError> {
+    let Query(query) = query?;
+    authorize_account(&state, &headers, &account)
+        .await
+        .map_err(|(_, message)| ApiError::unauthorized(message))?;
+
+    if !state.accounts.contains(&account).await {
+        return Err(ApiError::not_found(format!("unknown account {}", account)));
+    }
+
+    let token = match &query.token {
+        Some(token) => Some(
+            token
+                .parse::<Pubkey>()
+                .map_err(|err| ApiError::bad_request(format!("invalid token: {}", err)))?,
+        ),
+        None => None,
+    };
+    let after = query.cursor.as_deref().map(decode_cursor).transpose()?;
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    let db = state.memdb.read().await;
+    let range = match after {
+        Some(after) => (Bound::Excluded(after), Bound::Unbounded),
+        None => (Bound::Unbounded, Bound::Unbounded),
+    };
+
+    let mut matching = db
+        .notes(&account)
+        .into_iter()
+        .flat_map(|notes| notes.range(range))
+        .filter(|(_, note)| match query.status {
+            Some(status) => note.spent.is_some() == (status == NoteStatus::Spent),
+            None => true,
+        })
+        .filter(|(_, note)| token.is_none_or(|token| note.utxo.token_id() == token.to_bytes().to_vec()));
+
+    let page: Vec<(&NoteKey, &Note)> = matching.by_ref().take(limit).collect();
+    let next_cursor = match (page.last(), matching.next()) {
+        (Some((key, _)), Some(_)) => Some(encode_cursor(key)),
+        _ => None,
+    };
+
+    Ok(Json(NotesResponse {
+        notes: page.iter().map(|(key, note)| NoteView::new(key, note)).collect(),
+        next_cursor,
+    }))
+}
+
+/// Streams the account's note events and every root change as server-sent
+/// events. Each event id is its sequence number.
+pub async fn events(
+    State(state): State<Arc<AppState>>,
+    Path(account): Path<String>,
+    headers: HeaderMap,
+    query: Result<Query<EventsQuery>, QueryRejection>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    let Query(query) = query?;
+    authorize_account(&state, &headers, &account)
+        .await
+        .map_err(|(_, message)| ApiError::unauthorized(message))?;
+
+    if !state.accounts.contains(&account).await {
+        return Err(ApiError::not_found(format!("unknown account {}", account)));
+    }
+
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    let since = query.since.or(last_event_id);
+
+    let (backlog, receiver) = state.events.subscribe(since).await.map_err(ApiError::gone)?;
+
+    let backlog = backlog.into_iter().filter({
+        let account = account.clone();
+        move |event| event.visible_to(&account)
+    });
+    let live = stream::unfold((receiver, account.clone()), |(mut receiver, account)| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) if event.visible_to(&account) => return Some((event, (receiver, account))),
+                Ok(_) => continue,
+                // the client resumes from its last event id after reconnecting
+                Err(RecvError::Lagged(_)) | Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    let events = stream::iter(backlog).chain(live).map(|event| {
+        Ok(Event::default()
+            .id(event.seq.to_string())
+            .event(event.kind)
+            .data(event.data))
+    });
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(value: u8) -> Vec<u8> {
+        let mut leaf = vec![0; 32];
+        leaf[31] = value;
+        leaf
+    }
+
+    fn encode(bytes: &[u8]) -> String {
+        general_purpose::STANDARD.encode(bytes)
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        let key = NoteKey { tree_number: 3, leaf_index: 17 };
+
+        let decoded = decode_cursor(&encode_cursor(&key)).ok();
+        assert_eq!(decoded, Some(key));
+        assert!(decode_cursor("not a cursor").is_err());
+    }
+
+    #[tokio::test]
+    async fn looks_up_nullifiers_in_request_order() {
+        let state = Arc::new(AppState::for_tests(None));
+        let spent_by = TxRef {
+            signature: "spend".to_string(),
+            slot: 12,
+            finalized: true,
+            block_time: None,
+        };
+        state.memdb.write().await.insert_nullifiers(vec![leaf(2)], spent_by);
+
+        let request = NullifiersRequest { nullifiers: vec![encode(&leaf(1)), encode(&leaf(2))] };
+        let Json(response) = nullifiers(State(state.clone()), Json(request)).await.ok().unwrap();
+
+        let spent: Vec<(bool, Option<&str>)> = response
+            .nullifiers
+            .iter()
+            .map(|view| (view.spent, view.spent_by.as_ref().map(|tx| tx.signature.as_str())))
+            .collect();
+        assert_eq!(spent, vec![(false, None), (true, Some("spend"))]);
+
+        let invalid = NullifiersRequest { nullifiers: vec!["%%".to_string()] };
+        let status = nullifiers(State(state), Json(invalid)).await.err().map(|err| err.status);
+        assert_eq!(status, Some(StatusCode::BAD_REQUEST));
+    }
+
+    #[tokio::test]
+    async fn finds_roots_the_tree_had() {
+        let state = Arc::new(AppState::for_tests(None));
+        state.memdb.write().await.insert(0, 0, vec![leaf(1)], 10).unwrap();
+        let first_root = state.memdb.read().await.root(0).unwrap();
+        state.memdb.write().await.insert(0, 1, vec![leaf(2)], 11).unwrap();
+
+        let history = |tree_number, root: Option<String>| {
+            let query = RootHistoryQuery { root, limit: None };
+            root_history(State(state.clone()), Path(tree_number), Ok(Query(query)))
+        };
+
+        let Json(all) = history(0, None).await.ok().unwrap();
+        let counts: Vec<u64> = all.roots.iter().map(|record| record.leaf_count).collect();
+        assert_eq!(counts, vec![2, 1]);
+
+        let Json(found) = history(0, Some(encode(&first_root))).await.ok().unwrap();
+        assert_eq!(found.roots.len(), 1);
+        assert_eq!(found.roots[0].slot, 10);
+
+        let never = history(0, Some(encode(&leaf(9)))).await.err().map(|err| err.status);
+        assert_eq!(never, Some(StatusCode::NOT_FOUND));
+        let unknown = history(5, None).await.err().map(|err| err.status);
+        assert_eq!(unknown, Some(StatusCode::NOT_FOUND));
+    }
+
+    #[tokio::test]
+    async fn pages_leaves_up_to_the_frontier() {
+        let state = Arc::new(AppState::for_tests(None));
+        state.memdb.write().await.insert(0, 0, vec![leaf(1), leaf(2), leaf(3)], 10).unwrap();
+
+        let page = |from, limit| {
+            let query = LeavesQuery { from: Some(from), limit: Some(limit) };
+            leaves(State(state.clone()), Path(0), Ok(Query(query)))
+        };
+
+        let Json(first) = page(0, 2).await.ok().unwrap();
+        assert_eq!(first.leaves, vec![encode(&leaf(1)), encode(&leaf(2))]);
+        assert_eq!((first.next_leaf_index, first.leaf_count), (2, 3));
+        assert!(first.frontier.is_none());
+
+        let Json(last) = page(2, 2).await.ok().unwrap();
+        assert_eq!(last.leaves, vec![encode(&leaf(3))]);
+        let frontier = last.frontier.unwrap();
+        let path = state.memdb.read().await.tree(0).unwrap().merkle_path(2).unwrap();
+        let path: Vec<String> = path.iter().map(|sibling| encode(sibling)).collect();
+        assert_eq!(frontier.leaf_index, 2);
+        assert_eq!(frontier.siblings, path);
+
+        // caught up, nothing new yet
+        let Json(empty) = page(3, 2).await.ok().unwrap();
+        assert!(empty.leaves.is_empty());
+        let status = page(4, 2).await.err().map(|err| err.status);
+        assert_eq!(status, Some(StatusCode::BAD_REQUEST));
+    }
+}
@@ -0,0 +1,215 @@
+use std::sync::Arc;
+
+use axum::Json;
+use axum::extract::rejection::QueryRejection;
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api_handler::{
+        handler::{authorize_account, authorize_admin},
+        v2::ApiError,
+    },
+    webhook::{Delivery, DeliveryStatus},
+    AppState,
+};
+
+#[derive(Deserialize)]
+pub struct SetWebhook {
+    pub url: String,
+}
+
+#[derive(Serialize)]
+pub struct WebhookView {
+    pub url: String,
+    /// key of the HMAC in `X-Veil-Signature`, only returned when set
+    pub secret: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct DeliveriesQuery {
+    /// defaults to every delivery
+    pub status: Option<DeliveryStatus>,
+}
+
+#[derive(Serialize)]
+pub struct DeliveriesResponse {
+    /// newest first
+    pub deliveries: Vec<Delivery>,
+}
+
+#[derive(Deserialize)]
+pub struct ReplayQuery {
+    /// replay a single delivery, defaults to every failed one
+    pub id: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct ReplayResponse {
+    pub replayed: usize,
+}
+
+async fn authorize(state: &AppState, headers: &HeaderMap, account: &str) -> Result<(), ApiError> {
+    authorize_account(state, headers, account)
+        .await
+        .map_err(|(_, message)| ApiError::unauthorized(message))?;
+
+    if !state.accounts.contains(account).await {
+        return Err(ApiError::not_found(format!("unknown account {}", account)));
+    }
+
+    Ok(())
+}
+
+/// Points the account's webhook at a url and returns a fresh signing secret.
+/// The indexer sends the requests from inside its network, so only the
+/// operator holding the admin token picks where they go.
+pub async fn set_webhook(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(account): Path<String>,
+    Json(request): Json<SetWebhook>,
+) -> Result<Json<WebhookView>, ApiError> {
+    authorize_admin(&state, &headers).map_err(|(status, message)| match status {
+        StatusCode::FORBIDDEN => ApiError::forbidden(message),
+        _ => ApiError::unauthorized(message),
+    })?;
+    if !state.accounts.contains(&account).await {
+        return Err(ApiError::not_found(format!("unknown account {}", account)));
+    }
+
+    let secret = state
+        .webhooks
+        .set(&account, &request.url)
+        .await
+        .map_err(ApiError::bad_request)?;
+
+    Ok(Json(WebhookView {
+        url: request.url,
+        secret: Some(secret),
+    }))
+}
+
+pub async fn get_webhook(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(account): Path<String>,
+) -> Result<Json<WebhookView>, ApiError> {
+    authorize(&state, &headers, &account).await?;
+
+    let url = state
+        .webhooks
+        .url(&account)
+        .await
+        .ok_or_else(|| ApiError::not_found(format!("account {} has no webhook", account)))?;
+
+    Ok(Json(WebhookView { url, secret: None }))
+}
+
+pub async fn remove_webhook(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(account): Path<String>,
+) -> Result<Json<WebhookView>, ApiError> {
+    authorize(&state, &headers, &account).await?;
+
+    let url = state
+        .webhooks
+        .url(&account)
+        .await
+        .ok_or_else(|| ApiError::not_found(format!("account {} has no webhook", account)))?;
+    state.webhooks.remove(&account).await;
+
+    Ok(Json(WebhookView { url, secret: None }))
+}
+
+/// What was sent to the account's webhook and how it went.
+pub async fn deliveries(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(account): Path<String>,
+    query: Result<Query<DeliveriesQuery>, QueryRejection>,
+) -> Result<Json<DeliveriesResponse>, ApiError> {
+    let Query(query) = query?;
+    authorize(&state, &headers, &account).await?;
+
+    let deliveries = state
+        .webhooks
+        .deliveries(&account, query.status)
+        .await;
+
+    Ok(Json(DeliveriesResponse { deliveries }))
+}
+
+/// Sends failed deliveries again.
+pub async fn replay(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(account): Path<String>,
+    query: Result<Query<ReplayQuery>, QueryRejection>,
+) -> Result<Json<ReplayResponse>, ApiError> {
+    let Query(query) = query?;
+    authorize(&state, &headers, &account).await?;
+
+    let replayed = state
+        .webhooks
+        .replay(&account, query.id)
+        .await
+        .map_err(ApiError::not_found)?;
+
+    Ok(Json(ReplayResponse { replayed }))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{HeaderValue, header::AUTHORIZATION};
+    use axum::response::IntoResponse;
+
+    use super::*;
+    use crate::account::AccountKeys;
+
+    fn bearer(token: &str) -> HeaderMap {
+        let value = HeaderValue::from_str(&format!("Bearer {}", token)).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, value);
+        headers
+    }
+
+    #[tokio::test]
+    async fn only_the_admin_points_webhooks() {
+        let state = Arc::new(AppState::for_tests(Some("admin")));
+        let keys = AccountKeys::new(vec![1; 32], vec![2; 32]).unwrap();
+        state.accounts.insert("alice".to_string(), keys, "alice-token").await;
+
+        let set = |token: &str| {
+            let request = SetWebhook {
+                url: "http://127.0.0.1:9000/hook".to_string(),
+            };
+            set_webhook(
+                State(state.clone()),
+                bearer(token),
+                Path("alice".to_string()),
+                Json(request),
+            )
+        };
+
+        // the account's own token cannot send the indexer's requests elsewhere
+        let denied = set("alice-token").await.err().map(|err| err.into_response().status());
+        assert_eq!(denied, Some(StatusCode::UNAUTHORIZED));
+        assert!(state.webhooks.url("alice").await.is_none());
+
+        let Json(view) = set("admin").await.ok().unwrap();
+        assert!(view.secret.is_some());
+
+        // the account reads it back, without the secret
+        let read = get_webhook(
+            State(state.clone()),
+            bearer("alice-token"),
+            Path("alice".to_string()),
+        );
+        let Json(view) = read.await.ok().unwrap();
+        assert_eq!(view.url, "http://127.0.0.1:9000/hook");
+        assert!(view.secret.is_none());
+    }
+}
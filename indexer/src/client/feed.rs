@@ -0,0 +1,99 @@
+use std::collections::VecDeque;
+use std::future::Future;
+
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+use super::TransactionLogs;
+
+// transactions a producer may send ahead of the forwarder
+const FORWARD_BUFFER: usize = 100;
+
+/// What the historical and live tasks send to the ingestion loop.
+pub enum FeedItem {
+    Historical(TransactionLogs),
+    Live(TransactionLogs),
+    // a replay of history begins, live transactions wait until it is done
+    BackfillStarted,
+    BackfillDone,
+}
+
+/// Merges the historical and live feeds into one ordered stream.
+///
+/// Live transactions that arrive while history is replayed are held back
+/// and released by slot once the replay is done, so the replay always comes
+/// first and what it already covered is recognised as a duplicate instead
+/// of being interleaved with it.
+pub struct Feed {
+    rx: Receiver<FeedItem>,
+    backfilling: bool,
+    held: Vec<TransactionLogs>,
+    ready: VecDeque<TransactionLogs>,
+}
+
+impl Feed {
+    /// The ingestion loop starts out waiting for the initial backfill.
+    pub fn new(rx: Receiver<FeedItem>) -> Self {
+        Feed {
+            rx,
+            backfilling: true,
+            held: vec![],
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// Whether every transaction received so far was handed out and no
+    /// replay of history is running.
+    pub fn idle(&self) -> bool {
+        !self.backfilling && self.ready.is_empty() && self.rx.is_empty()
+    }
+
+    /// The next transaction to apply, `None` once every sender is gone. Safe
+    /// to cancel, nothing is lost when another branch of a `select!` wins.
+    pub async fn next(&mut self) -> Option<TransactionLogs> {
+        loop {
+            if let Some(tx_logs) = self.ready.pop_front() {
+                return Some(tx_logs);
+            }
+
+            match self.rx.recv().await? {
+                FeedItem::Historical(tx_logs) => return Some(tx_logs),
+                FeedItem::Live(tx_logs) if self.backfilling => self.held.push(tx_logs),
+                FeedItem::Live(tx_logs) => return Some(tx_logs),
+                FeedItem::BackfillStarted => self.backfilling = true,
+                FeedItem::BackfillDone => {
+                    self.backfilling = false;
+                    // stable, transactions of one slot keep their arrival order
+                    self.held.sort_by_key(|tx_logs| tx_logs.slot);
+                    self.ready.extend(self.held.drain(..));
+                }
+            }
+        }
+    }
+}
+
+/// Runs `produce` with a sender of its own and forwards everything it sends
+/// to `feed` as `wrap(tx_logs)`, in order. Returns once `produce` is done and
+/// all it sent was forwarded.
+pub async fn forward<F, Fut>(
+    feed: &Sender<FeedItem>,
+    wrap: fn(TransactionLogs) -> FeedItem,
+    produce: F,
+) -> Fut::Output
+where
+    F: FnOnce(Sender<TransactionLogs>) -> Fut,
+    Fut: Future,
+{
+    let (tx, mut rx) = mpsc::channel(FORWARD_BUFFER);
+
+    let forwarding = async {
+        while let Some(tx_logs) = rx.recv().await {
+            // dropping `rx` makes the producer's next send fail
+            if feed.send(wrap(tx_logs)).await.is_err() {
+                break;
+            }
+        }
+    };
+    let (output, ()) = tokio::join!(produce(tx), forwarding);
+
+    output
+}
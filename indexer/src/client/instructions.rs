@@ -0,0 +1,451 @@
+use borsh::BorshDeserialize;
+use darksol::{
+    DepositEvent, DepositRequest, NullifierEvent, TransactionEvent, TransferRequest,
+    WithdrawRequest,
+};
+
+use super::logs::{EventKind, ProgramEvent};
+
+// first byte of the instruction data, as written by the cli
+const DEPOSIT_VARIANT: u8 = 0;
+const TRANSFER_VARIANT: u8 = 1;
+const WITHDRAW_VARIANT: u8 = 2;
+
+/// An instruction of our program that appends commitments or spends notes.
+pub enum ProgramInstruction {
+    Deposit(DepositRequest),
+    Transfer(TransferRequest),
+    Withdraw(WithdrawRequest),
+}
+
+impl ProgramInstruction {
+    /// Decodes instruction data, `None` for instructions that emit no events
+    /// such as initialize.
+    pub fn decode(data: &[u8]) -> Result<Option<Self>, String> {
+        let (variant, request) = data
+            .split_first()
+            .ok_or("empty instruction data".to_string())?;
+
+        let instruction = match *variant {
+            DEPOSIT_VARIANT => {
+                DepositRequest::try_from_slice(request).map(ProgramInstruction::Deposit)
+            }
+            TRANSFER_VARIANT => {
+                TransferRequest::try_from_slice(request).map(ProgramInstruction::Transfer)
+            }
+            WITHDRAW_VARIANT => {
+                WithdrawRequest::try_from_slice(request).map(ProgramInstruction::Withdraw)
+            }
+            _ => return Ok(None),
+        };
+
+        instruction
+            .map(Some)
+            .map_err(|err| format!("cannot decode instruction data: {}", err))
+    }
+
+    /// Leaves the instruction appends, in order.
+    pub fn commitments(&self) -> Vec<Vec<u8>> {
+        match self {
+            ProgramInstruction::Deposit(request) => vec![request.pre_commitments.hash()],
+            ProgramInstruction::Transfer(request) => request.encrypted_commitments.clone(),
+            ProgramInstruction::Withdraw(request) => request.encrypted_commitments.clone(),
+        }
+    }
+
+    pub fn nullifiers(&self) -> Vec<Vec<u8>> {
+        match self {
+            ProgramInstruction::Deposit(_) => vec![],
+            ProgramInstruction::Transfer(request) => request.nullifiers.clone(),
+            ProgramInstruction::Withdraw(request) => request.nullifiers.clone(),
+        }
+    }
+
+    /// The events the program emits for this instruction when its
+    /// commitments land at `start_position` of tree `tree_number`.
+    pub fn into_events(
+        self,
+        tree_number: u64,
+        start_position: u64,
+    ) -> Result<Vec<(EventKind, Vec<u8>)>, String> {
+        let encode = |kind: EventKind, data: Result<Vec<u8>, std::io::Error>| {
+            data.map(|data| (kind, data)).map_err(|err| err.to_string())
+        };
+
+        match self {
+            ProgramInstruction::Deposit(request) => Ok(vec![encode(
+                EventKind::Deposit,
+                borsh::to_vec(&DepositEvent {
+                    pre_commitments: request.pre_commitments,
+                    shield_cipher_text: request.shield_cipher_text,
+                    tree_number,
+                    start_position,
+                }),
+            )?]),
+            ProgramInstruction::Transfer(request) => Ok(vec![
+                encode(
+                    EventKind::Transfer,
+                    borsh::to_vec(&TransactionEvent {
+                        commitments: request.encrypted_commitments,
+                        commitment_cipher_text: request.commitment_cipher_text,
+                        tree_number,
+                        start_position,
+                    }),
+                )?,
+                encode(
+                    EventKind::Nullifiers,
+                    borsh::to_vec(&NullifierEvent { nullifiers: request.nullifiers }),
+                )?,
+            ]),
+            ProgramInstruction::Withdraw(request) => Ok(vec![
+                encode(
+                    EventKind::Withdraw,
+                    borsh::to_vec(&TransactionEvent {
+                        commitments: request.encrypted_commitments,
+                        commitment_cipher_text: request.commitment_cipher_text,
+                        tree_number,
+                        start_position,
+                    }),
+                )?,
+                encode(
+                    EventKind::Nullifiers,
+                    borsh::to_vec(&NullifierEvent { nullifiers: request.nullifiers }),
+                )?,
+            ]),
+        }
+    }
+}
+
+// tree, start position and leaves of a logged event that appends commitments
+fn logged_commitments(event: &ProgramEvent) -> Option<(u64, u64, Vec<Vec<u8>>)> {
+    match event.kind {
+        EventKind::Deposit => {
+            let event = DepositEvent::try_from_slice(&event.data).ok()?;
+            Some((event.tree_number, event.start_position, vec![event.pre_commitments.hash()]))
+        }
+        EventKind::Transfer | EventKind::Withdraw => {
+            let event = TransactionEvent::try_from_slice(&event.data).ok()?;
+            Some((event.tree_number, event.start_position, event.commitments))
+        }
+        EventKind::Nullifiers => None,
+    }
+}
+
+fn logged_nullifiers(event: &ProgramEvent) -> Option<Vec<Vec<u8>>> {
+    match event.kind {
+        EventKind::Nullifiers => NullifierEvent::try_from_slice(&event.data)
+            .ok()
+            .map(|event| event.nullifiers),
+        _ => None,
+    }
+}
+
+/// Events of a transaction rebuilt from its instruction data.
+pub struct Recovered {
+    pub events: Vec<ProgramEvent>,
+    /// where the logs and the instruction data disagree
+    pub mismatches: Vec<String>,
+    // instructions appending commitments that logged no position, in order
+    unplaced: Vec<(usize, ProgramInstruction)>,
+    signature: String,
+    slot: u64,
+}
+
+impl Recovered {
+    /// Leaves of the instructions that logged no position, in order.
+    pub fn unplaced_leaves(&self) -> Vec<Vec<u8>> {
+        self.unplaced
+            .iter()
+            .flat_map(|(_, instruction)| instruction.commitments())
+            .collect()
+    }
+
+    /// Tree, start position and leaves of every batch whose position was
+    /// logged.
+    pub fn logged_batches(&self) -> Vec<(u64, u64, Vec<Vec<u8>>)> {
+        self.events.iter().filter_map(logged_commitments).collect()
+    }
+
+    /// The events of the transaction, those of instructions that logged no
+    /// position with their commitments in a row from `start_position` of
+    /// tree `tree_number`. Only call it with a position proven on chain.
+    pub fn place(self, tree_number: u64, start_position: u64) -> Result<Vec<ProgramEvent>, String> {
+        let Recovered {
+            mut events,
+            unplaced,
+            signature,
+            slot,
+            ..
+        } = self;
+
+        let mut next_leaf_index = start_position;
+        for (index, instruction) in unplaced {
+            let start_position = next_leaf_index;
+            next_leaf_index += instruction.commitments().len() as u64;
+
+            let rebuilt = instruction
+                .into_events(tree_number, start_position)
+                .map_err(|err| format!("instruction {}: cannot rebuild events: {}", index, err))?;
+            events.extend(
+                rebuilt
+                    .into_iter()
+                    .map(|(kind, data)| rebuilt_event(kind, data, &signature, slot, index)),
+            );
+        }
+
+        // stable, so events of one instruction keep their order
+        events.sort_by_key(|event| event.instruction_index);
+
+        Ok(events)
+    }
+}
+
+fn rebuilt_event(
+    kind: EventKind,
+    data: Vec<u8>,
+    signature: &str,
+    slot: u64,
+    instruction_index: usize,
+) -> ProgramEvent {
+    ProgramEvent {
+        kind,
+        data,
+        signature: signature.to_string(),
+        slot,
+        instruction_index,
+        invoke_depth: 1,
+    }
+}
+
+/// Rebuilds the events of a transaction whose logs were cut short from its
+/// instruction data.
+///
+/// `instructions` are our program's top level instructions with their index
+/// and `logged` the events that survived in the logs. Positions are taken
+/// from the logged events of an instruction. Those of an instruction that
+/// logged none cannot be told from the transaction alone, it is left
+/// unplaced until the chain proves where its commitments landed. When logs
+/// and instruction data disagree the logged events are kept and the mismatch
+/// is reported.
+pub fn recover_events(
+    instructions: Vec<(usize, Vec<u8>)>,
+    logged: &[ProgramEvent],
+    signature: &str,
+    slot: u64,
+) -> Recovered {
+    let mut recovered = Recovered {
+        events: vec![],
+        mismatches: vec![],
+        unplaced: vec![],
+        signature: signature.to_string(),
+        slot,
+    };
+
+    // events of instructions that reached our program through cpi have no
+    // instruction data of ours to rebuild from, they are kept as logged
+    let top_level: Vec<usize> = instructions.iter().map(|(index, _)| *index).collect();
+    recovered.events.extend(
+        logged
+            .iter()
+            .filter(|event| !top_level.contains(&event.instruction_index))
+            .cloned(),
+    );
+
+    for (index, data) in instructions {
+        let logged_here: Vec<&ProgramEvent> = logged
+            .iter()
+            .filter(|event| event.instruction_index == index)
+            .collect();
+
+        let instruction = match ProgramInstruction::decode(&data) {
+            Ok(Some(instruction)) => instruction,
+            Ok(None) => continue,
+            Err(err) => {
+                recovered.mismatches.push(format!("instruction {}: {}", index, err));
+                recovered.events.extend(logged_here.into_iter().cloned());
+                continue;
+            }
+        };
+        let commitments = instruction.commitments();
+        let mut disagrees = false;
+
+        let position = logged_here.iter().find_map(|event| logged_commitments(event));
+        if let Some((_, _, logged_leaves)) = &position {
+            if *logged_leaves != commitments {
+                recovered.mismatches.push(format!(
+                    "instruction {}: logged commitments differ from instruction data",
+                    index
+                ));
+                disagrees = true;
+            }
+        }
+
+        let spent = logged_here.iter().find_map(|event| logged_nullifiers(event));
+        if spent.is_some_and(|spent| spent != instruction.nullifiers()) {
+            recovered.mismatches.push(format!(
+                "instruction {}: logged nullifiers differ from instruction data",
+                index
+            ));
+            disagrees = true;
+        }
+
+        if disagrees {
+            recovered.events.extend(logged_here.into_iter().cloned());
+            continue;
+        }
+
+        let (tree_number, start_position) = match position {
+            Some((tree_number, start_position, _)) => (tree_number, start_position),
+            None => {
+                recovered.unplaced.push((index, instruction));
+                continue;
+            }
+        };
+        match instruction.into_events(tree_number, start_position) {
+            Ok(rebuilt) => recovered.events.extend(
+                rebuilt
+                    .into_iter()
+                    .map(|(kind, data)| rebuilt_event(kind, data, signature, slot, index)),
+            ),
+            Err(err) => {
+                recovered
+                    .mismatches
+                    .push(format!("instruction {}: cannot rebuild events: {}", index, err));
+                recovered.events.extend(logged_here.into_iter().cloned());
+            }
+        }
+    }
+
+    recovered.events.sort_by_key(|event| event.instruction_index);
+
+    recovered
+}
+
+#[cfg(test)]
+mod tests {
+    use darksol::{PreCommitments, ShieldCipherText};
+    use veil_types::UTXO;
+
+    use super::*;
+
+    fn random_bytes() -> Vec<u8> {
+        (0..32).map(|_| rand::random()).collect()
+    }
+
+    // instruction data of a deposit, as the cli writes it
+    fn deposit_data(amount: u64) -> Vec<u8> {
+        let (spending_key, viewing_key) = (random_bytes(), random_bytes());
+        let token = vec![1; 32];
+        let utxo = UTXO::new(
+            spending_key,
+            viewing_key.clone(),
+            token.clone(),
+            random_bytes(),
+            random_bytes(),
+            amount,
+            String::new(),
+        );
+        let pre_commitments = PreCommitments::new(amount, token, utxo.utxo_public_key());
+        let cipher = utxo.encrypt_for_deposit(viewing_key, random_bytes());
+        let shield_cipher_text =
+            ShieldCipherText::new(cipher.shield_key, cipher.cipher, utxo.nonce());
+        let request = DepositRequest::new(pre_commitments, shield_cipher_text);
+
+        let mut data = vec![DEPOSIT_VARIANT];
+        data.extend(borsh::to_vec(&request).unwrap());
+        data
+    }
+
+    // the events the program logs for `data` at `instruction_index`
+    fn logged(
+        data: &[u8],
+        instruction_index: usize,
+        tree_number: u64,
+        start: u64,
+    ) -> Vec<ProgramEvent> {
+        let instruction = ProgramInstruction::decode(data).unwrap().unwrap();
+        instruction
+            .into_events(tree_number, start)
+            .unwrap()
+            .into_iter()
+            .map(|(kind, data)| ProgramEvent {
+                kind,
+                data,
+                signature: "signature".to_string(),
+                slot: 42,
+                instruction_index,
+                invoke_depth: 1,
+            })
+            .collect()
+    }
+
+    fn positions(events: &[ProgramEvent]) -> Vec<(usize, u64, u64)> {
+        events
+            .iter()
+            .map(|event| {
+                let deposit = DepositEvent::try_from_slice(&event.data).unwrap();
+                (event.instruction_index, deposit.tree_number, deposit.start_position)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn leaves_unlogged_instructions_unplaced() {
+        let first = deposit_data(1);
+        let instructions = vec![(0, first.clone()), (1, vec![9]), (2, deposit_data(2))];
+
+        let recovered = recover_events(instructions, &[], "signature", 42);
+
+        assert!(recovered.mismatches.is_empty());
+        assert!(recovered.events.is_empty());
+        assert_eq!(recovered.unplaced_leaves().len(), 2);
+        assert_eq!(
+            recovered.unplaced_leaves()[0],
+            ProgramInstruction::decode(&first).unwrap().unwrap().commitments()[0]
+        );
+
+        // unknown variants such as initialize emit nothing
+        let events = recovered.place(3, 7).unwrap();
+        assert_eq!(positions(&events), vec![(0, 3, 7), (2, 3, 8)]);
+    }
+
+    #[test]
+    fn takes_positions_from_logged_events() {
+        let first = deposit_data(1);
+        let second = deposit_data(2);
+        let survived = logged(&first, 0, 2, 20);
+
+        let recovered =
+            recover_events(vec![(0, first), (1, second)], &survived, "signature", 42);
+
+        assert!(recovered.mismatches.is_empty());
+        assert_eq!(recovered.logged_batches().len(), 1);
+        assert_eq!(recovered.logged_batches()[0].1, 20);
+        let events = recovered.place(2, 21).unwrap();
+        assert_eq!(positions(&events), vec![(0, 2, 20), (1, 2, 21)]);
+    }
+
+    #[test]
+    fn keeps_logged_events_that_disagree() {
+        let survived = logged(&deposit_data(1), 0, 0, 5);
+
+        let recovered = recover_events(vec![(0, deposit_data(2))], &survived, "signature", 42);
+
+        assert_eq!(recovered.mismatches.len(), 1);
+        assert!(recovered.unplaced_leaves().is_empty());
+        assert_eq!(recovered.events.len(), 1);
+        assert_eq!(recovered.events[0].data, survived[0].data);
+    }
+
+    #[test]
+    fn keeps_events_logged_through_cpi() {
+        let through_cpi = logged(&deposit_data(1), 0, 0, 5);
+
+        let recovered =
+            recover_events(vec![(1, deposit_data(2))], &through_cpi, "signature", 42);
+
+        assert!(recovered.mismatches.is_empty());
+        let events = recovered.place(0, 6).unwrap();
+        assert_eq!(positions(&events), vec![(0, 0, 5), (1, 0, 6)]);
+    }
+}
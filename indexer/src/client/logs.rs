@@ -0,0 +1,363 @@
+use std::str::FromStr;
+
+use base64::{engine::general_purpose, Engine as _};
+use solana_sdk::pubkey::Pubkey;
+
+use super::{
+    DEPOSIT_EVENT, NULLIFIERS_EVENT, TRANSFER_EVENT, TransactionLogs, WITHDRAW_EVENT,
+};
+
+const LOG_PREFIX: &str = "Program log: ";
+const DATA_PREFIX: &str = "Program data: ";
+const TRUNCATED: &str = "Log truncated";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    Deposit,
+    Transfer,
+    Withdraw,
+    Nullifiers,
+}
+
+impl EventKind {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            DEPOSIT_EVENT => Some(EventKind::Deposit),
+            TRANSFER_EVENT => Some(EventKind::Transfer),
+            WITHDRAW_EVENT => Some(EventKind::Withdraw),
+            NULLIFIERS_EVENT => Some(EventKind::Nullifiers),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::Deposit => "deposit",
+            EventKind::Transfer => "transfer",
+            EventKind::Withdraw => "withdraw",
+            EventKind::Nullifiers => "nullifiers",
+        }
+    }
+}
+
+/// An event emitted by our program, with the instruction that emitted it.
+#[derive(Clone, Debug)]
+pub struct ProgramEvent {
+    pub kind: EventKind,
+    pub data: Vec<u8>, // borsh encoded event
+    pub signature: String,
+    pub slot: u64,
+    // position of the top level instruction in the transaction
+    pub instruction_index: usize,
+    // 1 when our program was invoked directly, higher through cpi
+    pub invoke_depth: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct ParsedLogs {
+    pub events: Vec<ProgramEvent>,
+    // lines that looked like our events but could not be parsed, with why
+    pub rejected: Vec<String>,
+    // the runtime cut the logs short, events past the cut are missing
+    pub truncated: bool,
+    // the invoke structure broke off, events past the break are missing
+    pub malformed: bool,
+    // a program failed, the runtime reverted the whole transaction and
+    // `events` is left empty
+    pub failed: bool,
+}
+
+/// Extracts our program's events from a transaction's logs.
+///
+/// The runtime brackets each program's output with `Program <id> invoke [n]`
+/// and `Program <id> success|failed`, so only lines printed while our
+/// program is on top of the invoke stack are considered. Events come either
+/// as `Program log: <name>, <base64>` or as `Program data: <base64 name>
+/// <base64 event>`. A `failed` exit anywhere means the transaction changed
+/// nothing, so no event of it is returned. Brackets that do not nest stop
+/// the parse and flag the logs `malformed`, like a truncation.
+pub fn parse_program_events(program_id: &Pubkey, tx_logs: &TransactionLogs) -> ParsedLogs {
+    let program_id = program_id.to_string();
+    let mut parsed = ParsedLogs::default();
+    let mut stack: Vec<String> = vec![];
+    let mut instruction_index: Option<usize> = None;
+
+    for (line_number, line) in tx_logs.logs.iter().enumerate() {
+        let reject = |reason: String| format!("line {}: {}: {:?}", line_number, reason, line);
+
+        if line == TRUNCATED {
+            parsed.truncated = true;
+            break;
+        }
+
+        if let Some((program, depth)) = parse_invoke(line) {
+            if depth != stack.len() + 1 {
+                parsed.rejected.push(reject(format!(
+                    "invoke depth {} does not follow depth {}",
+                    depth,
+                    stack.len()
+                )));
+                parsed.malformed = true;
+                break;
+            }
+            if depth == 1 {
+                instruction_index = Some(instruction_index.map_or(0, |index| index + 1));
+            }
+            stack.push(program.to_string());
+            continue;
+        }
+
+        if let Some((program, succeeded)) = parse_exit(line) {
+            if stack.last().map(String::as_str) != Some(program) {
+                parsed.rejected.push(reject("exit of a program that is not running".to_string()));
+                parsed.malformed = true;
+                break;
+            }
+            // a failing cpi cannot be caught, the whole transaction fails
+            if !succeeded {
+                parsed.failed = true;
+                parsed.events.clear();
+                break;
+            }
+            stack.pop();
+            continue;
+        }
+
+        // anything else was printed by the program on top of the stack
+        if stack.last() != Some(&program_id) {
+            continue;
+        }
+
+        let event = if let Some(message) = line.strip_prefix(LOG_PREFIX) {
+            match parse_log_message(message) {
+                Some(event) => event,
+                None => continue,
+            }
+        } else if let Some(fields) = line.strip_prefix(DATA_PREFIX) {
+            parse_data_fields(fields)
+        } else {
+            continue;
+        };
+
+        match event {
+            Ok((kind, data)) => parsed.events.push(ProgramEvent {
+                kind,
+                data,
+                signature: tx_logs.signature.clone(),
+                slot: tx_logs.slot,
+                instruction_index: instruction_index.unwrap_or_default(),
+                invoke_depth: stack.len(),
+            }),
+            Err(reason) => parsed.rejected.push(reject(reason)),
+        }
+    }
+
+    parsed
+}
+
+// splits `Program <id> <rest>`, only when <id> is a program id so program
+// output such as `Program log: ...` never matches
+fn split_program(line: &str) -> Option<(&str, &str)> {
+    let (program, rest) = line.strip_prefix("Program ")?.split_once(' ')?;
+    Pubkey::from_str(program).ok()?;
+
+    Some((program, rest))
+}
+
+// `Program <id> invoke [<depth>]`
+fn parse_invoke(line: &str) -> Option<(&str, usize)> {
+    let (program, rest) = split_program(line)?;
+    let depth = rest.strip_prefix("invoke [")?.strip_suffix(']')?.parse().ok()?;
+
+    Some((program, depth))
+}
+
+// `Program <id> success` or `Program <id> failed: <reason>`, with whether
+// the program succeeded
+fn parse_exit(line: &str) -> Option<(&str, bool)> {
+    let (program, rest) = split_program(line)?;
+
+    if rest == "success" {
+        Some((program, true))
+    } else if rest.starts_with("failed: ") {
+        Some((program, false))
+    } else {
+        None
+    }
+}
+
+// `<name>, <base64>`. Messages that do not start with an event name are
+// ordinary logging and yield `None`.
+fn parse_log_message(message: &str) -> Option<Result<(EventKind, Vec<u8>), String>> {
+    let (name, value) = match message.split_once(", ") {
+        Some(parts) => parts,
+        None => {
+            return EventKind::from_name(message.trim())
+                .map(|_| Err("event without a payload".to_string()));
+        }
+    };
+    let kind = EventKind::from_name(name)?;
+
+    Some(decode_payload(value).map(|data| (kind, data)))
+}
+
+// `<base64 name> <base64 event>`
+fn parse_data_fields(fields: &str) -> Result<(EventKind, Vec<u8>), String> {
+    let fields: Vec<&str> = fields.split(' ').collect();
+    if fields.len() != 2 {
+        return Err(format!("expected 2 data fields, got {}", fields.len()));
+    }
+
+    let name = decode_payload(fields[0])?;
+    let name = String::from_utf8(name).map_err(|_| "event name is not utf-8".to_string())?;
+    let kind = EventKind::from_name(&name).ok_or(format!("unknown event {}", name))?;
+
+    decode_payload(fields[1]).map(|data| (kind, data))
+}
+
+fn decode_payload(value: &str) -> Result<Vec<u8>, String> {
+    general_purpose::STANDARD
+        .decode(value)
+        .map_err(|err| format!("invalid base64 payload: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tx_logs(logs: Vec<String>) -> TransactionLogs {
+        TransactionLogs {
+            signature: "signature".to_string(),
+            slot: 42,
+            logs,
+            finalized: true,
+            block_time: None,
+        }
+    }
+
+    fn encode(data: &[u8]) -> String {
+        general_purpose::STANDARD.encode(data)
+    }
+
+    #[test]
+    fn attributes_events_to_their_instruction() {
+        let ours = Pubkey::new_unique();
+        let other = Pubkey::new_unique();
+        let logs = vec![
+            format!("Program {} invoke [1]", other),
+            format!("Program {} invoke [2]", ours),
+            format!("Program log: {}, {}", DEPOSIT_EVENT, encode(&[1])),
+            format!("Program {} success", ours),
+            // same shape, but printed by the calling program
+            format!("Program log: {}, {}", DEPOSIT_EVENT, encode(&[2])),
+            format!("Program {} success", other),
+            format!("Program {} invoke [1]", ours),
+            "Program log: ordinary logging".to_string(),
+            format!(
+                "Program data: {} {}",
+                encode(NULLIFIERS_EVENT.as_bytes()),
+                encode(&[3])
+            ),
+            format!("Program {} success", ours),
+        ];
+
+        let parsed = parse_program_events(&ours, &tx_logs(logs));
+
+        assert!(!parsed.failed && !parsed.truncated && !parsed.malformed);
+        assert!(parsed.rejected.is_empty());
+        let events: Vec<(EventKind, Vec<u8>, usize, usize)> = parsed
+            .events
+            .into_iter()
+            .map(|event| (event.kind, event.data, event.instruction_index, event.invoke_depth))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (EventKind::Deposit, vec![1], 0, 2),
+                (EventKind::Nullifiers, vec![3], 1, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn drops_every_event_of_a_failed_transaction() {
+        let ours = Pubkey::new_unique();
+        let logs = vec![
+            format!("Program {} invoke [1]", ours),
+            format!("Program log: {}, {}", DEPOSIT_EVENT, encode(&[1])),
+            format!("Program {} success", ours),
+            format!("Program {} invoke [1]", ours),
+            format!("Program log: {}, {}", TRANSFER_EVENT, encode(&[2])),
+            format!("Program {} failed: custom program error: 0x1", ours),
+        ];
+
+        let parsed = parse_program_events(&ours, &tx_logs(logs));
+
+        assert!(parsed.failed);
+        assert!(parsed.events.is_empty());
+    }
+
+    #[test]
+    fn keeps_events_before_a_truncation() {
+        let ours = Pubkey::new_unique();
+        let logs = vec![
+            format!("Program {} invoke [1]", ours),
+            format!("Program log: {}, {}", DEPOSIT_EVENT, encode(&[1])),
+            TRUNCATED.to_string(),
+        ];
+
+        let parsed = parse_program_events(&ours, &tx_logs(logs));
+
+        assert!(parsed.truncated);
+        assert_eq!(parsed.events.len(), 1);
+    }
+
+    #[test]
+    fn flags_logs_whose_invoke_structure_breaks() {
+        let ours = Pubkey::new_unique();
+        let other = Pubkey::new_unique();
+        let logs = vec![
+            format!("Program {} invoke [1]", ours),
+            format!("Program log: {}, {}", DEPOSIT_EVENT, encode(&[1])),
+            format!("Program {} success", ours),
+            format!("Program {} invoke [3]", ours),
+            format!("Program log: {}, {}", TRANSFER_EVENT, encode(&[2])),
+        ];
+
+        let parsed = parse_program_events(&ours, &tx_logs(logs));
+
+        assert!(parsed.malformed);
+        assert_eq!(parsed.rejected.len(), 1);
+        assert_eq!(parsed.events.len(), 1);
+
+        let logs = vec![
+            format!("Program {} invoke [1]", ours),
+            format!("Program {} success", other),
+            format!("Program log: {}, {}", DEPOSIT_EVENT, encode(&[1])),
+        ];
+
+        let parsed = parse_program_events(&ours, &tx_logs(logs));
+
+        assert!(parsed.malformed);
+        assert!(parsed.events.is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_events() {
+        let ours = Pubkey::new_unique();
+        let logs = vec![
+            format!("Program {} invoke [1]", ours),
+            format!("Program log: {}, not base64!", DEPOSIT_EVENT),
+            format!("Program log: {}", WITHDRAW_EVENT),
+            format!("Program data: {}", encode(&[1])),
+            format!("Program {} success", ours),
+        ];
+
+        let parsed = parse_program_events(&ours, &tx_logs(logs));
+
+        assert!(parsed.events.is_empty());
+        assert_eq!(parsed.rejected.len(), 3);
+        // a bad payload does not hide the events after it
+        assert!(!parsed.malformed);
+    }
+}
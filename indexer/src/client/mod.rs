@@ -1,21 +1,95 @@
-use std::collections::HashMap;
-
 use borsh::{BorshSerialize, BorshDeserialize};
-use veil_types::{UTXO, MerkleTreeSparse};
+use veil_types::UTXO;
 
+pub mod feed;
+pub mod instructions;
+pub mod logs;
 pub mod solana;
+pub mod source;
 
 pub const DEPOSIT_EVENT: &str = "deposit_event";
 pub const TRANSFER_EVENT: &str = "transfer_event";
 pub const WITHDRAW_EVENT: &str = "withdraw_event";
 pub const NULLIFIERS_EVENT: &str = "nullifiers_event";
 
+/// Position of a note's commitment: the tree it lives in and its leaf index.
+#[derive(
+    BorshSerialize, BorshDeserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub struct NoteKey {
+    pub tree_number: u64,
+    pub leaf_index: u64,
+}
+
+/// Program logs of one transaction together with where it landed.
+#[derive(Clone, Debug)]
+pub struct TransactionLogs {
+    pub signature: String,
+    pub slot: u64,
+    pub logs: Vec<String>,
+    // false while the transaction is only confirmed and may still be dropped
+    pub finalized: bool,
+    pub block_time: Option<i64>, // unix seconds, unknown for live logs
+}
+
+impl TransactionLogs {
+    pub fn tx_ref(&self) -> TxRef {
+        TxRef {
+            signature: self.signature.clone(),
+            slot: self.slot,
+            finalized: self.finalized,
+            block_time: self.block_time,
+        }
+    }
+}
+
+/// Transaction that created or spent a note.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TxRef {
+    pub signature: String,
+    pub slot: u64,
+    // false while the transaction is only confirmed and may still be dropped
+    pub finalized: bool,
+    pub block_time: Option<i64>, // unix seconds
+}
+
+/// How a note reached its owner.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NoteKind {
+    Deposit,
+    // returned to the sender by a transfer or withdraw that spent their notes
+    Change,
+    // sent by someone else
+    Incoming,
+}
+
+impl NoteKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NoteKind::Deposit => "deposit",
+            NoteKind::Change => "change",
+            NoteKind::Incoming => "incoming",
+        }
+    }
+}
+
 #[derive(BorshSerialize, BorshDeserialize, Clone, Debug)]
-pub struct RawData {
-    pub tree_data: MerkleTreeSparse<32>,
-    pub utxos_data: HashMap<u64, UTXO>,
+pub struct Note {
+    pub utxo: UTXO,
+    pub nullifier: Vec<u8>,
+    pub kind: NoteKind,
+    pub created_by: TxRef,
+    pub spent: Option<TxRef>,
+}
+
+/// A root a tree had, with the slot and leaf count that produced it.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RootRecord {
+    pub root: Vec<u8>,
+    pub slot: u64,
+    pub leaf_count: u64,
 }
 
 pub struct Data {
     pub data: String, // base64 encode of bytes data
-}
\ No newline at end of file
+}
@@ -1,82 +1,393 @@
-use std::{error::Error, str::FromStr};
+use std::{error::Error, str::FromStr, sync::Arc, time::Duration};
+
+use borsh::BorshDeserialize;
+use darksol::{
+    derive_pda,
+    state::{CommitmentsAccount, CommitmentsManagerAccount},
+};
 
 use futures::StreamExt;
+use rpc::RpcConfig;
 use solana_client::{
     nonblocking::{pubsub_client::PubsubClient, rpc_client::RpcClient},
-    rpc_config::RpcTransactionLogsConfig,
+    rpc_client::GetConfirmedSignaturesForAddress2Config,
+    rpc_config::{RpcTransactionConfig, RpcTransactionLogsConfig},
 };
 use solana_sdk::{commitment_config::CommitmentConfig, pubkey::Pubkey, signature::Signature};
-use solana_transaction_status::UiTransactionEncoding;
+use solana_transaction_status::{
+    TransactionConfirmationStatus, TransactionStatus, UiTransactionEncoding,
+};
+
+use super::TransactionLogs;
+use crate::{health::SyncStatus, metrics::Metrics};
+
+// delay before resubscribing after the log subscription dropped, doubled
+// on every failed attempt
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+// most signatures getSignaturesForAddress returns per request
+const SIGNATURE_PAGE_SIZE: usize = 1000;
 
 pub struct SolanaClient {
     client: RpcClient,
-    ws_client: PubsubClient,
+    // every subscription opens its own connection, a dropped one is not reused
+    ws_url: String,
+    metrics: Arc<Metrics>,
     // db: DbStorage,
 }
 
 impl SolanaClient {
-    pub async fn new(rpc_url: &str, ws_url: &str) -> Result<Self, Box<dyn Error>> {
-        let client = RpcClient::new(rpc_url.to_string());
-        let ws_client = PubsubClient::new(ws_url).await?;
+    pub fn new(
+        rpc_config: &RpcConfig,
+        ws_url: &str,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self, String> {
+        let client = rpc::new_client(rpc_config, CommitmentConfig::confirmed())?;
 
         Ok(SolanaClient {
             client,
-            ws_client,
+            ws_url: ws_url.to_string(),
+            metrics,
         })
     }
 
+    /// Streams the program's transactions as they land. The subscription is
+    /// re-established with backoff whenever it drops, and the transactions
+    /// that landed while it was down are fetched from history. Returns only
+    /// once `tx` is closed.
     pub async fn listen_to_program_logs(
         &self,
         program_id: Pubkey,
-        tx: tokio::sync::mpsc::Sender<Vec<String>>,
+        tx: tokio::sync::mpsc::Sender<TransactionLogs>,
+        sync: &SyncStatus,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut delay = MIN_RECONNECT_DELAY;
+        let mut reconnecting = false;
+
+        loop {
+            let result = self.subscribe_program_logs(program_id, &tx, sync, reconnecting).await;
+            sync.set_websocket_connected(false);
+            if tx.is_closed() {
+                return result;
+            }
+
+            match result {
+                // the connection was up until the stream closed
+                Ok(()) => delay = MIN_RECONNECT_DELAY,
+                Err(err) => sync.record_error(format!("log subscription failed: {}", err)),
+            }
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+
+            println!("log subscription dropped, reconnecting");
+            self.metrics.websocket_reconnects.inc();
+            reconnecting = true;
+        }
+    }
+
+    // one subscription, until its stream ends. After a reconnect the history
+    // since the last processed transaction is fetched once the new
+    // subscription is up, so whatever lands in between reaches `tx` through
+    // one or the other.
+    async fn subscribe_program_logs(
+        &self,
+        program_id: Pubkey,
+        tx: &tokio::sync::mpsc::Sender<TransactionLogs>,
+        sync: &SyncStatus,
+        fill_gap: bool,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let (mut subscription, _) = self
-            .ws_client
+        let ws_client = PubsubClient::new(&self.ws_url).await?;
+        let (mut subscription, _) = ws_client
             .logs_subscribe(
                 solana_client::rpc_config::RpcTransactionLogsFilter::Mentions(vec![
                     program_id.to_string(),
                 ]),
                 RpcTransactionLogsConfig {
-                    commitment: Some(CommitmentConfig::finalized()),
+                    commitment: Some(CommitmentConfig::confirmed()),
                 },
             )
             .await?;
 
+        if fill_gap {
+            // notifications queue up in the subscription meanwhile
+            let until = sync.cursor().or(sync.resume_point()).map(|cursor| cursor.signature);
+            self.fetch_historical_events(program_id, tx.clone(), until.as_deref())
+                .await?;
+        }
+        sync.set_websocket_connected(true);
+
         while let Some(logs_result) = subscription.next().await {
-            tx.send(logs_result.value.logs).await?;
+            // failed transactions are reverted, their logs describe nothing
+            if logs_result.value.err.is_some() {
+                continue;
+            }
+            tx.send(TransactionLogs {
+                signature: logs_result.value.signature,
+                slot: logs_result.context.slot,
+                logs: logs_result.value.logs,
+                finalized: false,
+                block_time: None,
+            })
+            .await?;
         }
 
         Ok(())
     }
 
+    /// Replays the program's transactions in the order they landed, stopping
+    /// at `until` when given.
     pub async fn fetch_historical_events(
         &self,
         program_id: Pubkey,
-        tx: tokio::sync::mpsc::Sender<Vec<String>>,
+        tx: tokio::sync::mpsc::Sender<TransactionLogs>,
+        until: Option<&str>,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let signatures = self.client.get_signatures_for_address(&program_id).await?;
-
-        for signature_info in signatures {
-            if let Ok(tx_result) = self
-                .client
-                .get_transaction(
-                    &Signature::from_str(&signature_info.signature).unwrap(),
-                    UiTransactionEncoding::Json,
+        let until = until.map(Signature::from_str).transpose()?;
+
+        // pages come newest first, each one ending before the last
+        let mut signatures = vec![];
+        let mut before = None;
+        loop {
+            let page = self
+                .metrics
+                .observe_rpc(
+                    "getSignaturesForAddress",
+                    self.client.get_signatures_for_address_with_config(
+                        &program_id,
+                        GetConfirmedSignaturesForAddress2Config {
+                            before,
+                            until,
+                            limit: Some(SIGNATURE_PAGE_SIZE),
+                            commitment: Some(CommitmentConfig::confirmed()),
+                        },
+                    ),
+                )
+                .await?;
+
+            let full = page.len() == SIGNATURE_PAGE_SIZE;
+            before = page
+                .last()
+                .map(|signature_info| Signature::from_str(&signature_info.signature))
+                .transpose()?;
+            signatures.extend(page);
+            if !full {
+                break;
+            }
+        }
+
+        // signatures come newest first, replay them in the order they landed.
+        // Failed transactions are reverted and skipped without fetching them
+        for signature_info in signatures.into_iter().rev().filter(|info| info.err.is_none()) {
+            let finalized = matches!(
+                signature_info.confirmation_status,
+                Some(TransactionConfirmationStatus::Finalized)
+            );
+
+            // skipping a transaction would leave a hole in the trees, so
+            // the replay stops here once retries are exhausted
+            let tx_result = self
+                .metrics
+                .observe_rpc(
+                    "getTransaction",
+                    self.client.get_transaction_with_config(
+                        &Signature::from_str(&signature_info.signature)?,
+                        RpcTransactionConfig {
+                            encoding: Some(UiTransactionEncoding::Json),
+                            commitment: Some(CommitmentConfig::confirmed()),
+                            max_supported_transaction_version: Some(0),
+                        },
+                    ),
                 )
                 .await
-            {
-                // Extract logs from transaction metadata
-                if let Some(meta) = &tx_result.transaction.meta {
-                    if let logs = &meta.log_messages.clone().unwrap() {
-                        tx.send(logs.clone()).await?;
-                    }
-                }
+                .map_err(|err| {
+                    format!("cannot fetch transaction {}: {}", signature_info.signature, err)
+                })?;
+
+            // Extract logs from transaction metadata, unless it failed after
+            // all. Nodes may omit logs, the ingestion loop then falls back
+            // to the instruction data
+            let meta = tx_result.transaction.meta.as_ref().filter(|meta| meta.err.is_none());
+            if let Some(meta) = meta {
+                let logs: Option<Vec<String>> = meta.log_messages.clone().into();
+                tx.send(TransactionLogs {
+                    signature: signature_info.signature.clone(),
+                    slot: tx_result.slot,
+                    logs: logs.unwrap_or_default(),
+                    finalized,
+                    block_time: tx_result.block_time,
+                })
+                .await?;
             }
         }
 
         Ok(())
     }
 
+    /// Data of the transaction's top level instructions that call
+    /// `program_id`, with their index. `None` when the transaction failed and
+    /// changed nothing.
+    pub async fn get_program_instructions(
+        &self,
+        signature: &str,
+        program_id: &Pubkey,
+    ) -> Result<Option<Vec<(usize, Vec<u8>)>>, Box<dyn Error + Send + Sync>> {
+        let tx_result = self
+            .metrics
+            .observe_rpc(
+                "getTransaction",
+                self.client.get_transaction_with_config(
+                    &Signature::from_str(signature)?,
+                    RpcTransactionConfig {
+                        encoding: Some(UiTransactionEncoding::Base64),
+                        commitment: Some(CommitmentConfig::confirmed()),
+                        max_supported_transaction_version: Some(0),
+                    },
+                ),
+            )
+            .await?;
+
+        if tx_result
+            .transaction
+            .meta
+            .as_ref()
+            .is_some_and(|meta| meta.err.is_some())
+        {
+            return Ok(None);
+        }
+
+        let transaction = tx_result
+            .transaction
+            .transaction
+            .decode()
+            .ok_or("cannot decode transaction")?;
+        // program ids are never loaded from lookup tables
+        let account_keys = transaction.message.static_account_keys();
+
+        let instructions = transaction
+            .message
+            .instructions()
+            .iter()
+            .enumerate()
+            .filter(|(_, instruction)| {
+                account_keys.get(instruction.program_id_index as usize) == Some(program_id)
+            })
+            .map(|(index, instruction)| (index, instruction.data.clone()))
+            .collect();
+
+        Ok(Some(instructions))
+    }
+
+    /// Estimated production time of a slot, in unix seconds.
+    pub async fn get_block_time(&self, slot: u64) -> Result<i64, Box<dyn Error + Send + Sync>> {
+        let block_time = self
+            .metrics
+            .observe_rpc("getBlockTime", self.client.get_block_time(slot))
+            .await?;
+
+        Ok(block_time)
+    }
+
+    /// Root and leaf count of a commitment tree as stored in the program's
+    /// account for it, at finalized commitment.
+    pub async fn get_tree_state(
+        &self,
+        program_id: &Pubkey,
+        tree_number: u64,
+    ) -> Result<(Vec<u8>, u64), Box<dyn Error + Send + Sync>> {
+        let (commitments_pda, _bump_seed) = derive_pda(tree_number, program_id);
+
+        let account = self
+            .metrics
+            .observe_rpc(
+                "getAccountInfo",
+                self.client
+                    .get_account_with_commitment(&commitments_pda, CommitmentConfig::finalized()),
+            )
+            .await?
+            .value
+            .ok_or(format!("commitments account of tree {} does not exist", tree_number))?;
+        let commitments = CommitmentsAccount::try_from_slice(&account.data)?;
+
+        Ok((commitments.root(), commitments.next_leaf_index))
+    }
+
+    /// Number of the tree the program appends commitments to, at finalized
+    /// commitment.
+    pub async fn get_current_tree_number(
+        &self,
+        program_id: &Pubkey,
+    ) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        let (manager_pda, _bump_seed) =
+            Pubkey::find_program_address(&[b"commitments_manager_pda"], program_id);
+
+        let account = self
+            .metrics
+            .observe_rpc(
+                "getAccountInfo",
+                self.client
+                    .get_account_with_commitment(&manager_pda, CommitmentConfig::finalized()),
+            )
+            .await?
+            .value
+            .ok_or("commitments manager account does not exist")?;
+        let manager = CommitmentsManagerAccount::try_from_slice(&account.data)?;
+
+        Ok(manager.incremental_tree_number)
+    }
+
+    pub async fn get_finalized_slot(&self) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        let slot = self
+            .metrics
+            .observe_rpc(
+                "getSlot",
+                self.client.get_slot_with_commitment(CommitmentConfig::finalized()),
+            )
+            .await?;
+
+        Ok(slot)
+    }
+
+    /// Latest confirmed slot of the cluster, recorded as the chain tip.
+    pub async fn get_tip_slot(&self) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        let slot = self
+            .metrics
+            .observe_rpc(
+                "getSlot",
+                self.client.get_slot_with_commitment(CommitmentConfig::confirmed()),
+            )
+            .await?;
+        self.metrics.chain_tip(slot);
+
+        Ok(slot)
+    }
+
+    /// Looks up the status of each signature, including ones older than the
+    /// node's recent status cache. `None` means the cluster does not know it.
+    pub async fn get_signature_statuses(
+        &self,
+        signatures: &[String],
+    ) -> Result<Vec<Option<TransactionStatus>>, Box<dyn Error + Send + Sync>> {
+        let mut statuses = vec![];
+
+        // the rpc accepts at most 256 signatures per request
+        for chunk in signatures.chunks(256) {
+            let chunk = chunk
+                .iter()
+                .map(|signature| Signature::from_str(signature))
+                .collect::<Result<Vec<_>, _>>()?;
+            let response = self
+                .metrics
+                .observe_rpc(
+                    "getSignatureStatuses",
+                    self.client.get_signature_statuses_with_history(&chunk),
+                )
+                .await?;
+            statuses.extend(response.value);
+        }
+
+        Ok(statuses)
+    }
+
     // pub fn from_json(&mut self, json_data: Data) -> Self {
     //     let dencoded = general_purpose::STANDARD.decode(json_data.data).unwrap();
     //     let raw_data = RawData::try_from_slice(&dencoded).unwrap();
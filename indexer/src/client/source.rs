@@ -0,0 +1,209 @@
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use solana_sdk::pubkey::Pubkey;
+use tokio::sync::mpsc::Sender;
+
+use super::{TransactionLogs, solana::SolanaClient};
+use crate::{health::SyncStatus, metrics::Metrics};
+
+type SourceResult = Result<(), Box<dyn Error + Send + Sync>>;
+
+/// Where the ingestion loop gets transaction logs from. Every source feeds
+/// the same channel, so recorded and live transactions are processed alike.
+pub trait EventSource: Send + Sync {
+    /// Sends past transactions in the order they landed, stopping at
+    /// `until` when given.
+    fn backfill<'a>(
+        &'a self,
+        tx: Sender<TransactionLogs>,
+        until: Option<&'a str>,
+    ) -> BoxFuture<'a, SourceResult>;
+
+    /// Sends transactions as they land, returns when the feed ends.
+    fn listen<'a>(
+        &'a self,
+        tx: Sender<TransactionLogs>,
+        sync: &'a SyncStatus,
+    ) -> BoxFuture<'a, SourceResult>;
+}
+
+/// Live cluster: signature history over rpc and a log subscription.
+pub struct SolanaSource {
+    client: Arc<SolanaClient>,
+    program_id: Pubkey,
+}
+
+impl SolanaSource {
+    pub fn new(client: Arc<SolanaClient>, program_id: Pubkey) -> Self {
+        SolanaSource { client, program_id }
+    }
+}
+
+impl EventSource for SolanaSource {
+    fn backfill<'a>(
+        &'a self,
+        tx: Sender<TransactionLogs>,
+        until: Option<&'a str>,
+    ) -> BoxFuture<'a, SourceResult> {
+        Box::pin(self.client.fetch_historical_events(self.program_id, tx, until))
+    }
+
+    fn listen<'a>(
+        &'a self,
+        tx: Sender<TransactionLogs>,
+        sync: &'a SyncStatus,
+    ) -> BoxFuture<'a, SourceResult> {
+        Box::pin(self.client.listen_to_program_logs(self.program_id, tx, sync))
+    }
+}
+
+/// One line of a recording: a transaction and where it landed.
+#[derive(Serialize, Deserialize)]
+pub struct RecordedTransaction {
+    pub signature: String,
+    pub slot: u64,
+    pub logs: Vec<String>,
+    // archived transactions are usually final, so this defaults to true
+    #[serde(default = "finalized_by_default")]
+    pub finalized: bool,
+    #[serde(default)]
+    pub block_time: Option<i64>,
+}
+
+fn finalized_by_default() -> bool {
+    true
+}
+
+impl From<RecordedTransaction> for TransactionLogs {
+    fn from(record: RecordedTransaction) -> Self {
+        TransactionLogs {
+            signature: record.signature,
+            slot: record.slot,
+            logs: record.logs,
+            finalized: record.finalized,
+            block_time: record.block_time,
+        }
+    }
+}
+
+impl From<&TransactionLogs> for RecordedTransaction {
+    fn from(tx_logs: &TransactionLogs) -> Self {
+        RecordedTransaction {
+            signature: tx_logs.signature.clone(),
+            slot: tx_logs.slot,
+            logs: tx_logs.logs.clone(),
+            finalized: tx_logs.finalized,
+            block_time: tx_logs.block_time,
+        }
+    }
+}
+
+/// Replays a JSONL recording of transactions, one `RecordedTransaction` per
+/// line, without talking to a cluster.
+pub struct FileSource {
+    path: PathBuf,
+    metrics: Arc<Metrics>,
+}
+
+impl FileSource {
+    pub fn new(path: PathBuf, metrics: Arc<Metrics>) -> Self {
+        FileSource { path, metrics }
+    }
+
+    async fn replay(&self, tx: Sender<TransactionLogs>, until: Option<&str>) -> SourceResult {
+        let file = File::open(&self.path)
+            .map_err(|err| format!("cannot open recording {:?}: {}", self.path, err))?;
+
+        let mut records = vec![];
+        for (number, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let record: RecordedTransaction = serde_json::from_str(&line)
+                .map_err(|err| format!("invalid record on line {}: {}", number + 1, err))?;
+            records.push(record);
+        }
+
+        // like the rpc history, `until` is the newest transaction already
+        // applied, so only what was recorded after it is replayed
+        if let Some(until) = until {
+            if let Some(position) = records.iter().position(|record| record.signature == until) {
+                records.drain(..=position);
+            }
+        }
+
+        // the recording stands in for the chain, its last slot is the tip
+        if let Some(last) = records.iter().map(|record| record.slot).max() {
+            self.metrics.chain_tip(last);
+        }
+
+        for record in records {
+            tx.send(record.into()).await?;
+        }
+
+        Ok(())
+    }
+}
+
+impl EventSource for FileSource {
+    fn backfill<'a>(
+        &'a self,
+        tx: Sender<TransactionLogs>,
+        until: Option<&'a str>,
+    ) -> BoxFuture<'a, SourceResult> {
+        Box::pin(self.replay(tx, until))
+    }
+
+    fn listen<'a>(
+        &'a self,
+        _tx: Sender<TransactionLogs>,
+        sync: &'a SyncStatus,
+    ) -> BoxFuture<'a, SourceResult> {
+        // a recording has no live feed that could drop
+        sync.set_websocket_connected(true);
+
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Appends every processed transaction to a JSONL file that `FileSource`
+/// can replay later. Transactions are recorded as delivered, so confirmed
+/// ones keep `finalized: false`.
+pub struct Recorder {
+    file: Mutex<File>,
+}
+
+impl Recorder {
+    pub fn open(path: PathBuf) -> Result<Self, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|err| format!("cannot open recording {:?}: {}", path, err))?;
+
+        Ok(Recorder {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(&self, tx_logs: &TransactionLogs) -> Result<(), String> {
+        let mut line = serde_json::to_string(&RecordedTransaction::from(tx_logs))
+            .map_err(|err| format!("cannot encode record: {}", err))?;
+        line.push('\n');
+
+        // a single write per line keeps lines whole if the process dies
+        self.file
+            .lock()
+            .unwrap()
+            .write_all(line.as_bytes())
+            .map_err(|err| format!("cannot write record: {}", err))
+    }
+}
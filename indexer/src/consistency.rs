@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+use solana_sdk::pubkey::Pubkey;
+
+use crate::{
+    AppState, api_handler::v2::RootView, client::solana::SolanaClient,
+    storage::db::memdb::MemDb,
+};
+
+/// Compares every tree with the program's account for it and resyncs the
+/// trees that diverged from the last leaf count that was known good.
+pub struct TreeChecker {
+    program_id: Pubkey,
+    // leaf count and root per tree that last matched the chain
+    verified: HashMap<u64, (u64, Vec<u8>)>,
+    // on-chain leaf count per tree the index was behind on at the last check
+    behind: HashMap<u64, u64>,
+}
+
+impl TreeChecker {
+    pub fn new(program_id: Pubkey) -> Self {
+        TreeChecker {
+            program_id,
+            verified: HashMap::new(),
+            behind: HashMap::new(),
+        }
+    }
+
+    pub async fn check(
+        &mut self,
+        client: &SolanaClient,
+        state: &AppState,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // a replay in progress is behind by design
+        if !state.sync.backfill_complete() {
+            self.behind.clear();
+            return Ok(());
+        }
+
+        let tree_numbers: Vec<u64> = state
+            .memdb
+            .read()
+            .await
+            .roots()
+            .into_iter()
+            .map(|(tree_number, _, _)| tree_number)
+            .collect();
+
+        for tree_number in tree_numbers {
+            let (chain_root, chain_leaf_count) =
+                client.get_tree_state(&self.program_id, tree_number).await?;
+
+            let diverged = self
+                .compare(state, tree_number, &chain_root, chain_leaf_count)
+                .await;
+            if let Some(reason) = diverged {
+                self.resync(state, tree_number, reason).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    // returns why the tree diverged, if it did
+    async fn compare(
+        &mut self,
+        state: &AppState,
+        tree_number: u64,
+        chain_root: &[u8],
+        chain_leaf_count: u64,
+    ) -> Option<String> {
+        let db = state.memdb.read().await;
+        let tree = db.tree(tree_number)?;
+        let leaf_count = tree.next_leaf_index();
+
+        // the chain is read at finalized, so the index is usually ahead of
+        // it and only roots at the same leaf count can be compared
+        if leaf_count >= chain_leaf_count {
+            self.behind.remove(&tree_number);
+
+            let record = tree
+                .root_history()
+                .find(|record| record.leaf_count == chain_leaf_count);
+            return match record {
+                Some(record) if record.root == chain_root => {
+                    self.verified.insert(tree_number, (chain_leaf_count, record.root.clone()));
+                    None
+                }
+                Some(_) => Some(format!("root at {} leaves differs from chain", chain_leaf_count)),
+                None => Some(format!(
+                    "never had {} leaves, the chain's leaf count",
+                    chain_leaf_count
+                )),
+            };
+        }
+
+        // lagging is normal while events are in flight, still lagging behind
+        // the same count a whole check later means leaves were missed
+        let missed = self
+            .behind
+            .insert(tree_number, chain_leaf_count)
+            .is_some_and(|previous| previous > leaf_count);
+        missed.then(|| {
+            format!(
+                "stuck at {} leaves while the chain has {}",
+                leaf_count, chain_leaf_count
+            )
+        })
+    }
+
+    async fn resync(&mut self, state: &AppState, tree_number: u64, reason: String) {
+        let mut db = state.memdb.write().await;
+        let good_leaf_count = self.good_leaf_count(&db, tree_number);
+        state.metrics.tree_diverged(tree_number);
+        state.sync.record_error(format!(
+            "tree {} diverged from chain: {}, resyncing from leaf {}",
+            tree_number, reason, good_leaf_count
+        ));
+
+        let dropped = db.reset_tree(tree_number, good_leaf_count);
+        if !dropped.is_empty() {
+            state.sync.record_error(format!(
+                "dropped {} notes of tree {} for resync",
+                dropped.len(),
+                tree_number
+            ));
+        }
+
+        let roots = db.roots();
+        state.metrics.set_tree_sizes(&roots);
+        let root = roots.into_iter().find(|(number, _, _)| *number == tree_number);
+        let (root, leaf_count) = root
+            .map(|(_, root, leaf_count)| (root, leaf_count))
+            .unwrap_or_default();
+        state.events.root_changed(RootView::new(tree_number, &root, leaf_count)).await;
+        self.behind.remove(&tree_number);
+
+        // replaying history puts the dropped leaves back in place, they may
+        // predate an imported snapshot
+        state.sync.clear_resume_point();
+        let _ = state.refetch_tx.try_send(());
+    }
+
+    // the leaf count last verified, while the tree still has the root that
+    // was verified there. An import may have replaced the leaves since, a
+    // tree never verified is resynced from its first leaf
+    fn good_leaf_count(&self, db: &MemDb, tree_number: u64) -> u64 {
+        let (leaf_count, root) = match self.verified.get(&tree_number) {
+            Some(verified) => verified,
+            None => return 0,
+        };
+
+        let still_there = db.tree(tree_number).is_some_and(|tree| {
+            tree.root_history()
+                .any(|record| record.leaf_count == *leaf_count && record.root == *root)
+        });
+        if still_there { *leaf_count } else { 0 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(value: u8) -> Vec<u8> {
+        let mut leaf = vec![0; 32];
+        leaf[31] = value;
+        leaf
+    }
+
+    #[test]
+    fn trusts_verified_counts_only_while_the_root_is_kept() {
+        let mut db = MemDb::new();
+        db.insert(0, 0, vec![leaf(1)], 10).unwrap();
+        let verified_root = db.root(0).unwrap();
+        db.insert(0, 1, vec![leaf(2)], 11).unwrap();
+
+        let mut checker = TreeChecker::new(Pubkey::default());
+        assert_eq!(checker.good_leaf_count(&db, 0), 0);
+
+        checker.verified.insert(0, (1, verified_root));
+        assert_eq!(checker.good_leaf_count(&db, 0), 1);
+
+        // an imported snapshot replaced the tree with other leaves
+        let mut imported = MemDb::new();
+        imported.insert(0, 0, vec![leaf(7), leaf(2)], 11).unwrap();
+        assert_eq!(checker.good_leaf_count(&imported, 0), 0);
+    }
+}
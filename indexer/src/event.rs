@@ -1,117 +1,316 @@
+use std::sync::Arc;
+
 use borsh::BorshDeserialize;
 use darksol::{DepositEvent, NullifierEvent, TransactionEvent};
+use rayon::prelude::*;
 use veil_types::{CipherText, DepositCiphertext, UTXO};
 
-use crate::get_key_from_file;
+use crate::account::AccountKeys;
+use crate::client::{
+    NoteKey, TxRef,
+    logs::{EventKind, ProgramEvent},
+};
+
+/// Transaction and instruction an event was emitted by.
+#[derive(Clone, Debug)]
+pub struct EventMeta {
+    pub tx: TxRef,
+    pub instruction_index: usize,
+}
 
-#[derive(Debug, serde::Serialize)]
-pub struct Event {
-    pub event_type: String,
-    pub value: String,
+/// A decoded event of our program, as handed to the processors. Events
+/// carrying cipher texts are shared with the blocking decryption task.
+pub enum IndexerEvent {
+    Deposit { meta: EventMeta, event: Arc<DepositEvent> },
+    Transfer { meta: EventMeta, event: Arc<TransactionEvent> },
+    Withdraw { meta: EventMeta, event: Arc<TransactionEvent> },
+    Nullifiers { meta: EventMeta, event: NullifierEvent },
 }
 
-impl Event {
-    pub fn parse_event(log: &str) -> Option<Event> {
-        let parts: Vec<&str> = log.split(", ").collect();
-        if parts.len() == 2 {
-            return Some(Event {
-                event_type: parts[0].to_string(),
-                value: parts[1].to_string(),
-            });
+impl IndexerEvent {
+    pub fn decode(event: &ProgramEvent, tx: &TxRef) -> Result<Self, String> {
+        let meta = EventMeta {
+            tx: tx.clone(),
+            instruction_index: event.instruction_index,
+        };
+        let invalid = |err: std::io::Error| format!("invalid {} event: {}", event.kind.as_str(), err);
+
+        let decoded = match event.kind {
+            EventKind::Deposit => IndexerEvent::Deposit {
+                meta,
+                event: Arc::new(DepositEvent::try_from_slice(&event.data).map_err(invalid)?),
+            },
+            EventKind::Transfer => IndexerEvent::Transfer {
+                meta,
+                event: Arc::new(TransactionEvent::try_from_slice(&event.data).map_err(invalid)?),
+            },
+            EventKind::Withdraw => IndexerEvent::Withdraw {
+                meta,
+                event: Arc::new(TransactionEvent::try_from_slice(&event.data).map_err(invalid)?),
+            },
+            EventKind::Nullifiers => IndexerEvent::Nullifiers {
+                meta,
+                event: NullifierEvent::try_from_slice(&event.data).map_err(invalid)?,
+            },
+        };
+
+        Ok(decoded)
+    }
+
+    pub fn kind(&self) -> EventKind {
+        match self {
+            IndexerEvent::Deposit { .. } => EventKind::Deposit,
+            IndexerEvent::Transfer { .. } => EventKind::Transfer,
+            IndexerEvent::Withdraw { .. } => EventKind::Withdraw,
+            IndexerEvent::Nullifiers { .. } => EventKind::Nullifiers,
+        }
+    }
+
+    pub fn meta(&self) -> &EventMeta {
+        match self {
+            IndexerEvent::Deposit { meta, .. }
+            | IndexerEvent::Transfer { meta, .. }
+            | IndexerEvent::Withdraw { meta, .. }
+            | IndexerEvent::Nullifiers { meta, .. } => meta,
+        }
+    }
+
+    /// Tree, start position and leaves of an event that appends commitments.
+    pub fn commitments(&self) -> Option<(u64, u64, Vec<Vec<u8>>)> {
+        match self {
+            IndexerEvent::Deposit { event, .. } => Some((
+                event.tree_number,
+                event.start_position,
+                vec![event.pre_commitments.hash()],
+            )),
+            IndexerEvent::Transfer { event, .. } | IndexerEvent::Withdraw { event, .. } => Some((
+                event.tree_number,
+                event.start_position,
+                event.commitments.clone(),
+            )),
+            IndexerEvent::Nullifiers { .. } => None,
+        }
+    }
+
+    pub fn nullifiers(&self) -> &[Vec<u8>] {
+        match self {
+            IndexerEvent::Nullifiers { event, .. } => &event.nullifiers,
+            _ => &[],
         }
-        None
     }
 }
 
-pub fn decrypt_transaction_cipher_text(
-    key_path: String,
-    value: Vec<u8>,
-) -> Result<(Vec<UTXO>, Vec<Vec<u8>>, u64, u64), String> {
-    let (viewing_key, spending_key, _deposit_key) = match get_key_from_file(key_path) {
-        Ok(data) => data,
-        Err(err) => return Err(err.to_string()),
-    };
-    let event = match TransactionEvent::try_from_slice(&value) {
-        Ok(data) => data,
-        Err(err) => return Err(err.to_string()),
-    };
+/// A note an account could decrypt, at the position of its commitment.
+pub struct DecryptedNote {
+    pub account: String,
+    pub key: NoteKey,
+    pub utxo: UTXO,
+}
 
+/// Trial-decrypts every commitment of a transaction event with each account's
+/// keys.
+///
+/// Every (account, cipher text) pair is a task of its own on the rayon pool,
+/// so one account and a large batch still use every core. Call it from a
+/// blocking context. Notes come back ordered by leaf position, then by
+/// account.
+pub fn decrypt_transaction_cipher_text(
+    accounts: &[(String, AccountKeys)],
+    event: &TransactionEvent,
+) -> Result<Vec<DecryptedNote>, String> {
     if event.commitment_cipher_text.len() != event.commitments.len() {
         return Err("commitments len and cipher text len must be equal".to_string());
     }
 
-    let mut utxos: Vec<UTXO> = vec![];
-    let mut leafs: Vec<Vec<u8>> = vec![];
-    for idx in 0..event.commitment_cipher_text.len() {
-        let text = event.commitment_cipher_text[idx].clone();
-        leafs.push(event.commitments[idx].clone());
-
-        let utxo = match UTXO::decrypt(
-            CipherText {
-                cipher: text.ciphertext,
-                nonce: text.nonce,
-                blinded_sender_pubkey: text.encrypted_sender_key,
-                blinded_receiver_pubkey: text.encrypted_receiver_key,
-            },
-            viewing_key.clone(),
-            spending_key.clone(),
-        ) {
-            Ok(utxo) => utxo,
-            Err(_err) => {
-                continue;
+    let texts = &event.commitment_cipher_text;
+    let mut found: Vec<(usize, usize, UTXO)> = (0..accounts.len() * texts.len())
+        .into_par_iter()
+        .filter_map(|pair| {
+            let (account_idx, idx) = (pair / texts.len(), pair % texts.len());
+            let (_, keys) = &accounts[account_idx];
+            let text = &texts[idx];
+            let utxo = UTXO::decrypt(
+                CipherText {
+                    cipher: text.ciphertext.clone(),
+                    nonce: text.nonce.clone(),
+                    blinded_sender_pubkey: text.encrypted_sender_key.clone(),
+                    blinded_receiver_pubkey: text.encrypted_receiver_key.clone(),
+                },
+                keys.viewing_key.clone(),
+                keys.spending_key.clone(),
+            )
+            .ok()?;
+
+            if utxo.utxo_hash() != event.commitments[idx] {
+                println!("inserted commitments non valid");
+                return None;
             }
-        };
 
-        if utxo.utxo_hash() != event.commitments[idx] {
-            println!("inserted commitments non valid");
-            continue;
-        }
+            Some((idx, account_idx, utxo))
+        })
+        .collect();
+    found.sort_by_key(|(idx, account_idx, _)| (*idx, *account_idx));
 
-        utxos.push(utxo);
-    }
+    let notes = found
+        .into_iter()
+        .map(|(idx, account_idx, utxo)| DecryptedNote {
+            account: accounts[account_idx].0.clone(),
+            key: NoteKey {
+                tree_number: event.tree_number,
+                leaf_index: event.start_position + idx as u64,
+            },
+            utxo,
+        })
+        .collect();
 
-    Ok((utxos, leafs, event.tree_number, event.start_position))
+    Ok(notes)
 }
 
+/// Trial-decrypts a deposit event with each account's keys.
+///
+/// Accounts are tried in parallel on the rayon pool, so call it from a
+/// blocking context.
 pub fn decrypt_deposit_cipher_text(
-    key_path: String,
-    value: Vec<u8>,
-) -> Result<(UTXO, u64, u64), String> {
-    let (viewing_key, spending_key, _deposit_key) = match get_key_from_file(key_path) {
-        Ok(data) => data,
-        Err(err) => return Err(err.to_string()),
-    };
-    let event = match DepositEvent::try_from_slice(&value) {
-        Ok(data) => data,
-        Err(err) => return Err(err.to_string()),
+    accounts: &[(String, AccountKeys)],
+    event: &DepositEvent,
+) -> Vec<DecryptedNote> {
+    let commitment = event.pre_commitments.hash();
+    let text = &event.shield_cipher_text;
+    let key = NoteKey {
+        tree_number: event.tree_number,
+        leaf_index: event.start_position,
     };
 
-    let text = event.shield_cipher_text;
-    let utxo = match UTXO::decrypt_for_deposit(
-        DepositCiphertext {
-            cipher: text.encrypted_text,
-            nonce: text.nonce,
-            shield_key: text.shield_key,
-        },
-        event.pre_commitments.token_id,
-        event.pre_commitments.value,
-        viewing_key,
-        spending_key,
-    ) {
-        Ok(utxo) => utxo,
-        Err(err) => {
-            return Err(err.to_string());
-        }
-    };
+    accounts
+        .par_iter()
+        .filter_map(|(account, keys)| {
+            let utxo = UTXO::decrypt_for_deposit(
+                DepositCiphertext {
+                    cipher: text.encrypted_text.clone(),
+                    nonce: text.nonce.clone(),
+                    shield_key: text.shield_key.clone(),
+                },
+                event.pre_commitments.token_id.clone(),
+                event.pre_commitments.value,
+                keys.viewing_key.clone(),
+                keys.spending_key.clone(),
+            )
+            .ok()?;
+
+            if utxo.utxo_hash() != commitment {
+                println!("deposit commitment non valid");
+                return None;
+            }
 
-    Ok((utxo, event.tree_number, event.start_position))
+            Some(DecryptedNote {
+                account: account.clone(),
+                key,
+                utxo,
+            })
+        })
+        .collect()
 }
 
-pub fn get_nullifiers_from_event(value: Vec<u8>) -> Result<Vec<Vec<u8>>, String> {
-    let event = match NullifierEvent::try_from_slice(&value) {
-        Ok(data) => data,
-        Err(err) => return Err(format!("failed to deserialize nullifiers event: {}", err.to_string()))
-    };
+#[cfg(test)]
+mod tests {
+    use darksol::CommitmentCipherText;
+
+    use super::*;
+
+    fn random_bytes() -> Vec<u8> {
+        (0..32).map(|_| rand::random()).collect()
+    }
+
+    // change note of `keys`, encrypted the way the cli does
+    fn change(keys: &AccountKeys, amount: u64) -> (Vec<u8>, CommitmentCipherText) {
+        let utxo = UTXO::new(
+            keys.spending_key.clone(),
+            keys.viewing_key.clone(),
+            vec![1; 32],
+            random_bytes(),
+            random_bytes(),
+            amount,
+            String::new(),
+        );
+        let cipher_text = utxo.encrypt(keys.viewing_key.clone());
+
+        let text = CommitmentCipherText::new(
+            cipher_text.blinded_sender_pubkey,
+            cipher_text.cipher,
+            cipher_text.blinded_receiver_pubkey,
+            utxo.nonce(),
+            vec![],
+        );
+        (utxo.utxo_hash(), text)
+    }
 
-    Ok(event.nullifiers)
-}
\ No newline at end of file
+    #[test]
+    fn decrypts_every_pair_in_leaf_order() {
+        let alice = AccountKeys::new(random_bytes(), random_bytes()).unwrap();
+        let bob = AccountKeys::new(random_bytes(), random_bytes()).unwrap();
+        let accounts = vec![
+            ("alice".to_string(), alice.clone()),
+            ("bob".to_string(), bob.clone()),
+        ];
+
+        let (commitments, commitment_cipher_text) =
+            [change(&alice, 1), change(&bob, 2), change(&alice, 3)].into_iter().unzip();
+        let event = TransactionEvent {
+            commitments,
+            commitment_cipher_text,
+            tree_number: 2,
+            start_position: 40,
+        };
+
+        let notes = decrypt_transaction_cipher_text(&accounts, &event).unwrap();
+        let found: Vec<(&str, u64, u64)> = notes
+            .iter()
+            .map(|note| (note.account.as_str(), note.key.leaf_index, note.utxo.amount()))
+            .collect();
+        assert_eq!(found, vec![("alice", 40, 1), ("bob", 41, 2), ("alice", 42, 3)]);
+        assert!(notes.iter().all(|note| note.key.tree_number == 2));
+    }
+
+    #[test]
+    fn decodes_events_with_their_transaction() {
+        let tx = TxRef {
+            signature: "signature".to_string(),
+            slot: 42,
+            finalized: false,
+            block_time: Some(1_700_000_000),
+        };
+        let mut logged = ProgramEvent {
+            kind: EventKind::Nullifiers,
+            data: borsh::to_vec(&NullifierEvent { nullifiers: vec![vec![7; 32]] }).unwrap(),
+            signature: tx.signature.clone(),
+            slot: tx.slot,
+            instruction_index: 2,
+            invoke_depth: 1,
+        };
+
+        let event = IndexerEvent::decode(&logged, &tx).unwrap();
+        assert_eq!(event.kind(), EventKind::Nullifiers);
+        assert_eq!(event.meta().instruction_index, 2);
+        assert_eq!(event.meta().tx, tx);
+        assert_eq!(event.nullifiers(), &[vec![7; 32]]);
+        assert!(event.commitments().is_none());
+
+        // the payload has to be the event its kind names
+        logged.kind = EventKind::Transfer;
+        assert!(IndexerEvent::decode(&logged, &tx).is_err());
+    }
+
+    #[test]
+    fn rejects_mismatched_cipher_texts() {
+        let alice = AccountKeys::new(random_bytes(), random_bytes()).unwrap();
+        let (commitment, _) = change(&alice, 1);
+        let event = TransactionEvent {
+            commitments: vec![commitment],
+            commitment_cipher_text: vec![],
+            tree_number: 0,
+            start_position: 0,
+        };
+
+        assert!(decrypt_transaction_cipher_text(&[("alice".to_string(), alice)], &event).is_err());
+    }
+}
@@ -0,0 +1,167 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::Serialize;
+
+/// Position of the ingestion loop: the last transaction it applied.
+#[derive(Serialize, BorshSerialize, BorshDeserialize, Clone, Debug)]
+pub struct Cursor {
+    pub signature: String,
+    pub slot: u64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct LastError {
+    pub message: String,
+    pub at: u64, // unix seconds
+}
+
+/// Sync state shared between the background tasks and the health endpoints.
+pub struct SyncStatus {
+    backfill_complete: AtomicBool,
+    websocket_connected: AtomicBool,
+    cursor: Mutex<Option<Cursor>>,
+    // position of the imported snapshot, history before it is not replayed
+    resume_point: Mutex<Option<Cursor>>,
+    // newest finalized transaction applied while no tree was missing leaves,
+    // history before it never needs replaying to fill a gap
+    checkpoint: Mutex<Option<Cursor>>,
+    last_error: Mutex<Option<LastError>>,
+}
+
+impl SyncStatus {
+    pub fn new() -> Self {
+        SyncStatus {
+            backfill_complete: AtomicBool::new(false),
+            websocket_connected: AtomicBool::new(false),
+            cursor: Mutex::new(None),
+            resume_point: Mutex::new(None),
+            checkpoint: Mutex::new(None),
+            last_error: Mutex::new(None),
+        }
+    }
+
+    pub fn backfill_complete(&self) -> bool {
+        self.backfill_complete.load(Ordering::Relaxed)
+    }
+
+    pub fn set_backfill_complete(&self) {
+        self.backfill_complete.store(true, Ordering::Relaxed);
+    }
+
+    pub fn websocket_connected(&self) -> bool {
+        self.websocket_connected.load(Ordering::Relaxed)
+    }
+
+    pub fn set_websocket_connected(&self, connected: bool) {
+        self.websocket_connected.store(connected, Ordering::Relaxed);
+    }
+
+    pub fn cursor(&self) -> Option<Cursor> {
+        self.cursor.lock().unwrap().clone()
+    }
+
+    pub fn advance(&self, signature: &str, slot: u64) {
+        let mut cursor = self.cursor.lock().unwrap();
+        // historical replays must not move the cursor backwards
+        if cursor.as_ref().is_some_and(|cursor| cursor.slot > slot) {
+            return;
+        }
+        *cursor = Some(Cursor {
+            signature: signature.to_string(),
+            slot,
+        });
+    }
+
+    pub fn resume_point(&self) -> Option<Cursor> {
+        self.resume_point.lock().unwrap().clone()
+    }
+
+    /// Moves the cursor to an imported snapshot and stops historical
+    /// replays from going further back than it.
+    pub fn resume_from(&self, cursor: Option<Cursor>) {
+        *self.cursor.lock().unwrap() = cursor.clone();
+        *self.resume_point.lock().unwrap() = cursor;
+        // it belonged to the replaced state
+        *self.checkpoint.lock().unwrap() = None;
+    }
+
+    /// Lets the next historical replay go back to the program's first
+    /// transaction, for state that has to be rebuilt from before a snapshot.
+    pub fn clear_resume_point(&self) {
+        *self.resume_point.lock().unwrap() = None;
+        *self.checkpoint.lock().unwrap() = None;
+    }
+
+    /// Records a finalized transaction applied while every tree was
+    /// complete. Older ones are ignored.
+    pub fn set_checkpoint(&self, signature: &str, slot: u64) {
+        let mut checkpoint = self.checkpoint.lock().unwrap();
+        if checkpoint.as_ref().is_some_and(|checkpoint| checkpoint.slot > slot) {
+            return;
+        }
+        *checkpoint = Some(Cursor {
+            signature: signature.to_string(),
+            slot,
+        });
+    }
+
+    /// Where a replay of history stops: the newest checkpoint, else the
+    /// imported snapshot. `None` replays from the program's first
+    /// transaction.
+    pub fn replay_until(&self) -> Option<Cursor> {
+        let checkpoint = self.checkpoint.lock().unwrap().clone();
+        checkpoint.or_else(|| self.resume_point())
+    }
+
+    pub fn last_error(&self) -> Option<LastError> {
+        self.last_error.lock().unwrap().clone()
+    }
+
+    /// Logs an error from a background task and keeps it for the health
+    /// endpoints.
+    pub fn record_error(&self, message: String) {
+        println!("{}", message);
+
+        let at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        *self.last_error.lock().unwrap() = Some(LastError { message, at });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cursor(signature: &str, slot: u64) -> Option<(String, u64)> {
+        Some((signature.to_string(), slot))
+    }
+
+    fn replay_until(sync: &SyncStatus) -> Option<(String, u64)> {
+        sync.replay_until().map(|cursor| (cursor.signature, cursor.slot))
+    }
+
+    #[test]
+    fn bounds_replays_by_the_newest_complete_point() {
+        let sync = SyncStatus::new();
+        assert_eq!(replay_until(&sync), None);
+
+        sync.set_checkpoint("a", 10);
+        sync.set_checkpoint("older", 5);
+        assert_eq!(replay_until(&sync), cursor("a", 10));
+
+        // an import replaces the state the checkpoint was taken of
+        sync.resume_from(Some(Cursor { signature: "snapshot".to_string(), slot: 8 }));
+        assert_eq!(replay_until(&sync), cursor("snapshot", 8));
+        sync.set_checkpoint("b", 12);
+        assert_eq!(replay_until(&sync), cursor("b", 12));
+
+        // state rebuilt from the first transaction
+        sync.clear_resume_point();
+        assert_eq!(replay_until(&sync), None);
+    }
+}
@@ -1,16 +1,61 @@
 use serde::Serialize;
 use std::fs;
-use tokio::sync::Mutex;
+use std::sync::Arc;
+use tokio::sync::{RwLock, mpsc};
 
+use account::Accounts;
+use health::SyncStatus;
+use metrics::Metrics;
+use notifier::EventHub;
+use snapshot::Import;
+use storage::db::memdb::MemDb;
+use webhook::Webhooks;
+
+pub mod account;
 pub mod api_handler;
 pub mod client;
+pub mod consistency;
 pub mod event;
+pub mod health;
+pub mod metrics;
+pub mod notifier;
+pub mod processor;
+pub mod snapshot;
 pub mod storage;
+pub mod webhook;
 
 const CONTENT_LENGTH: usize = 96;
 
 // Define application state
-pub type AppState = Mutex<String>;
+pub struct AppState {
+    pub memdb: Arc<RwLock<MemDb>>, // written by the ingestion loop, read by handlers
+    pub accounts: Arc<Accounts>,
+    pub admin_token: Option<String>,
+    pub refetch_tx: mpsc::Sender<()>, // asks the historical task to replay history
+    pub imports: mpsc::Sender<Import>, // uploaded snapshots, swapped in by the ingestion loop
+    pub events: Arc<EventHub>,
+    pub metrics: Arc<Metrics>,
+    pub sync: Arc<SyncStatus>,
+    pub webhooks: Arc<Webhooks>,
+}
+
+#[cfg(test)]
+impl AppState {
+    // state for handler tests, nothing listens on the channels
+    pub fn for_tests(admin_token: Option<&str>) -> Self {
+        AppState {
+            memdb: Arc::new(RwLock::new(MemDb::new())),
+            accounts: Arc::new(Accounts::new()),
+            admin_token: admin_token.map(str::to_string),
+            refetch_tx: mpsc::channel(1).0,
+            imports: mpsc::channel(1).0,
+            events: Arc::new(EventHub::new()),
+            metrics: Arc::new(Metrics::new().unwrap()),
+            sync: Arc::new(SyncStatus::new()),
+            webhooks: Arc::new(Webhooks::new()),
+        }
+    }
+}
 
 #[derive(Serialize)]
 pub struct Data {
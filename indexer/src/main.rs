@@ -1,24 +1,55 @@
-use axum::{Router, routing::get};
-use base64::Engine as _;
-use base64::engine::general_purpose;
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::{
+    Router,
+    extract::DefaultBodyLimit,
+    routing::{delete, get, post},
+};
+use rpc::RpcConfig;
+use solana_sdk::{commitment_config::CommitmentConfig, pubkey::Pubkey};
+use tokio::{
+    net::TcpListener,
+    sync::{RwLock, mpsc},
+};
+
 use indexer::{
     AppState,
-    api_handler::handler::{leafs, roots},
+    account::{AccountKeys, Accounts, DEFAULT_ACCOUNT, generate_token, write_token_file},
+    api_handler::{
+        handler::{
+            export_snapshot, import_snapshot, leafs, register_account, remove_account, roots,
+        },
+        status,
+        v2::{self, RootView},
+        webhook,
+    },
     client::{
-        DEPOSIT_EVENT, NULLIFIERS_EVENT, TRANSFER_EVENT, WITHDRAW_EVENT, solana::SolanaClient,
+        TransactionLogs,
+        feed::{Feed, FeedItem, forward},
+        instructions::recover_events,
+        logs::{ProgramEvent, parse_program_events},
+        solana::SolanaClient,
+        source::{EventSource, FileSource, Recorder, SolanaSource},
     },
-    event::{
-        Event, decrypt_deposit_cipher_text, decrypt_transaction_cipher_text,
-        get_nullifiers_from_event,
+    consistency::TreeChecker,
+    event::IndexerEvent,
+    get_key_from_file,
+    health::{Cursor, SyncStatus},
+    metrics::Metrics,
+    notifier::EventHub,
+    processor::{
+        Pipeline, TxContext, metrics::MetricsProcessor, notify::NotifyProcessor,
+        store::StoreProcessor, webhook::WebhookProcessor,
     },
+    snapshot::{self, Import, Prepared},
     storage::db::memdb::MemDb,
-};
-use solana_sdk::pubkey::Pubkey;
-use std::{error::Error, str::FromStr};
-use std::{net::SocketAddr, sync::Arc};
-use tokio::{
-    net::TcpListener,
-    sync::{Mutex, mpsc},
+    webhook::{NOTE_FINALIZED, WebhookPayload, Webhooks},
 };
 
 // const RPC_URL: &str = "https://api.mainnet-beta.solana.com";
@@ -28,124 +59,701 @@ const RPC_URL: &str = "https://api.testnet.solana.com";
 const WS_URL: &str = "wss://api.testnet.solana.com/";
 const KEY_PATH: &str = "../../../darksol-data/key";
 const PROGRAM_ID: &str = "";
+// how often provisional transactions are checked for finality
+const FINALITY_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+// bearer token required to register or remove accounts
+const ADMIN_TOKEN_ENV: &str = "INDEXER_ADMIN_TOKEN";
+// bearer token serving the default account's notes, generated when unset
+const DEFAULT_ACCOUNT_TOKEN_ENV: &str = "INDEXER_ACCOUNT_TOKEN";
+// file a generated default account token is written to, owner readable only
+const DEFAULT_ACCOUNT_TOKEN_FILE_ENV: &str = "INDEXER_ACCOUNT_TOKEN_FILE";
+const DEFAULT_ACCOUNT_TOKEN_FILE: &str = "default-account-token";
+// how often the chain tip is sampled for the lag metric
+const TIP_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+// how often trees are compared with their on-chain accounts
+const ROOT_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+// snapshot file to bootstrap from instead of replaying all history
+const SNAPSHOT_IMPORT_ENV: &str = "INDEXER_SNAPSHOT_IMPORT";
+// directory periodic snapshots are written to, disabled when unset
+const SNAPSHOT_DIR_ENV: &str = "INDEXER_SNAPSHOT_DIR";
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// periodic snapshots kept before the oldest is deleted
+const SNAPSHOT_KEEP: usize = 24;
+// uploaded snapshots hold every tree, well past axum's default body limit
+const MAX_SNAPSHOT_SIZE: usize = 1 << 30;
+// JSONL recording replayed instead of connecting to the cluster
+const REPLAY_FILE_ENV: &str = "INDEXER_REPLAY_FILE";
+// JSONL file every processed transaction is appended to
+const RECORD_FILE_ENV: &str = "INDEXER_RECORD_FILE";
+// comma separated rpc urls tried in order, RPC_URL when unset
+const RPC_URLS_ENV: &str = "INDEXER_RPC_URLS";
+// requests per second sent to each rpc url, unlimited when unset
+const RPC_RATE_LIMIT_ENV: &str = "INDEXER_RPC_RATE_LIMIT";
+// retries of a failed rpc request and the pauses between them, the rpc
+// crate's defaults when unset
+const RPC_MAX_RETRIES_ENV: &str = "INDEXER_RPC_MAX_RETRIES";
+const RPC_INITIAL_BACKOFF_MS_ENV: &str = "INDEXER_RPC_INITIAL_BACKOFF_MS";
+const RPC_MAX_BACKOFF_MS_ENV: &str = "INDEXER_RPC_MAX_BACKOFF_MS";
+// pause before rerunning a historical replay that failed
+const BACKFILL_RETRY_DELAY: Duration = Duration::from_secs(30);
+// how often finalized transactions behind the cursor are forgotten
+const APPLIED_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+// set to run as a public service: every tree and nullifier is indexed, no
+// keys are loaded and the account endpoints are left out
+const PUBLIC_MODE_ENV: &str = "INDEXER_PUBLIC";
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let program_id = Pubkey::from_str(PROGRAM_ID)?;
+    let public = std::env::var(PUBLIC_MODE_ENV).is_ok();
+
+    let metrics = Arc::new(Metrics::new()?);
+
+    // a recording replaces the cluster entirely, nothing is fetched live
+    let (source, client) = match std::env::var(REPLAY_FILE_ENV) {
+        Ok(path) => {
+            println!("replaying recorded transactions from {}", path);
+            let source = FileSource::new(PathBuf::from(path), metrics.clone());
+            (Arc::new(source) as Arc<dyn EventSource>, None)
+        }
+        Err(_) => {
+            let client = Arc::new(SolanaClient::new(&rpc_config(), WS_URL, metrics.clone())?);
+            let source = SolanaSource::new(client.clone(), program_id);
+            (Arc::new(source) as Arc<dyn EventSource>, Some(client))
+        }
+    };
+    let recorder = match std::env::var(RECORD_FILE_ENV) {
+        Ok(path) => Some(Recorder::open(PathBuf::from(path))?),
+        Err(_) => None,
+    };
+    let memdb = Arc::new(RwLock::new(MemDb::new()));
+    let sync = Arc::new(SyncStatus::new());
+
+    let accounts = Arc::new(Accounts::new());
+
+    // the key file, when present, becomes the default account
+    let key_file = if public {
+        Err("public mode, no keys are loaded".to_string())
+    } else {
+        get_key_from_file(KEY_PATH.to_string())
+    };
+    match key_file {
+        Ok((spending_key, viewing_key, _deposit_key)) => {
+            let token = match std::env::var(DEFAULT_ACCOUNT_TOKEN_ENV) {
+                Ok(token) => token,
+                Err(_) => {
+                    // kept off stdout, logs are often shipped elsewhere
+                    let path = std::env::var(DEFAULT_ACCOUNT_TOKEN_FILE_ENV)
+                        .unwrap_or(DEFAULT_ACCOUNT_TOKEN_FILE.to_string());
+                    let token = generate_token();
+                    write_token_file(Path::new(&path), &token)?;
+                    println!(
+                        "access token for the {} account written to {}",
+                        DEFAULT_ACCOUNT, path
+                    );
+                    token
+                }
+            };
+
+            accounts
+                .insert(
+                    DEFAULT_ACCOUNT.to_string(),
+                    AccountKeys::new(viewing_key, spending_key)?,
+                    &token,
+                )
+                .await;
+        }
+        Err(err) => println!("no default account registered: {}", err),
+    }
 
-    let solana_client = SolanaClient::new(RPC_URL, WS_URL);
-    let client = Arc::new(solana_client.await?);
-    let memdb = Arc::new(Mutex::new(MemDb::new()));
+    // after the keys, a snapshot without notes has to replay history for them
+    if let Ok(path) = std::env::var(SNAPSHOT_IMPORT_ENV) {
+        let bytes = std::fs::read(&path)?;
+        let prepared = snapshot::prepare(&bytes, !accounts.is_empty().await)?;
+        if let Some(client) = &client {
+            let unverified = snapshot::verify_on_chain(&prepared, client, &program_id).await?;
+            if !unverified.is_empty() {
+                println!("trees {:?} of the snapshot are behind the chain, unverified", unverified);
+            }
+        }
+        let cursor = snapshot::restore(&memdb, &sync, &metrics, prepared).await;
+        println!("bootstrapped from snapshot {}, resuming after {:?}", path, cursor);
+    }
 
-    let (tx, mut rx) = mpsc::channel(100);
+    let (feed_tx, feed_rx) = mpsc::channel(100);
+    let (refetch_tx, mut refetch_rx) = mpsc::channel::<()>(1);
+    let (imports_tx, mut imports_rx) = mpsc::channel::<Import>(1);
 
     // Spawn WebSocket listener for real-time indexing
     tokio::spawn({
-        let client = client.clone();
-        let tx = tx.clone();
-        async move { client.listen_to_program_logs(program_id, tx).await }
+        let source = source.clone();
+        let feed_tx = feed_tx.clone();
+        let sync = sync.clone();
+        async move {
+            let listened = forward(&feed_tx, FeedItem::Live, |tx| source.listen(tx, &sync)).await;
+            if let Err(err) = listened {
+                sync.set_websocket_connected(false);
+                sync.record_error(format!("error listening to program logs: {}", err));
+            }
+        }
     });
 
-    // Spawn a task for historical indexing
+    // Spawn a task for historical indexing, re-run whenever a tree has a gap
     tokio::spawn({
-        let client = client.clone();
-        let tx = tx.clone();
-        async move { client.fetch_historical_events(program_id, tx).await }
-    });
+        let source = source.clone();
+        let feed_tx = feed_tx.clone();
+        let sync = sync.clone();
+        async move {
+            // history before an imported snapshot or the last point every
+            // tree was complete at is already in the state, until an
+            // account registered later needs its older notes
+            let until = || sync.replay_until().map(|cursor| cursor.signature);
+
+            let mut refetch = false;
+            let mut failed = false;
+            loop {
+                if refetch {
+                    // a replay that gave up after its rpc retries is rerun
+                    // without waiting for a refetch request
+                    if failed {
+                        tokio::time::sleep(BACKFILL_RETRY_DELAY).await;
+                    } else if refetch_rx.recv().await.is_none() {
+                        break;
+                    }
+                    let _ = feed_tx.send(FeedItem::BackfillStarted).await;
+                }
 
-    // get initial json state
-    let memdb_json_data = memdb.lock().await.to_json();
+                let until = until();
+                let backfilled = forward(&feed_tx, FeedItem::Historical, |tx| {
+                    source.backfill(tx, until.as_deref())
+                })
+                .await;
+                // live transactions are released even when the replay failed,
+                // a later refetch fills what it missed
+                let _ = feed_tx.send(FeedItem::BackfillDone).await;
+
+                failed = backfilled.is_err();
+                match backfilled {
+                    Ok(()) => sync.set_backfill_complete(),
+                    Err(err) => sync.record_error(format!("error fetching historical events: {}", err)),
+                }
+                refetch = true;
+            }
+        }
+    });
 
     // Create shared state
-    let shared_state: Arc<AppState> = Arc::new(Mutex::new(memdb_json_data.data.clone()));
+    let shared_state: Arc<AppState> = Arc::new(AppState {
+        memdb: memdb.clone(),
+        accounts: accounts.clone(),
+        admin_token: std::env::var(ADMIN_TOKEN_ENV).ok(),
+        refetch_tx: refetch_tx.clone(),
+        imports: imports_tx,
+        events: Arc::new(EventHub::new()),
+        webhooks: Arc::new(Webhooks::new()),
+        metrics: metrics.clone(),
+        sync: sync.clone(),
+    });
 
     let worker_state = Arc::clone(&shared_state);
 
-    // start api server
-    let app = Router::new()
+    // start api server, the public routes serve chain data only
+    let mut app = Router::new()
         .route("/root", get(roots))
-        .route("/notes", get(leafs))
-        .with_state(shared_state);
+        .route(
+            "/admin/snapshot",
+            get(export_snapshot)
+                .post(import_snapshot)
+                .layer(DefaultBodyLimit::max(MAX_SNAPSHOT_SIZE)),
+        )
+        .route("/health", get(status::health))
+        .route("/ready", get(status::ready))
+        .route("/metrics", get(status::metrics))
+        .route("/v2/roots", get(v2::roots))
+        .route("/v2/trees/{tree_number}/leaves", get(v2::leaves))
+        .route("/v2/trees/{tree_number}/roots", get(v2::root_history))
+        .route(
+            "/v2/trees/{tree_number}/leaves/{leaf_index}/path",
+            get(v2::merkle_path),
+        )
+        .route("/v2/nullifiers", post(v2::nullifiers));
+    if !public {
+        app = app.merge(account_routes());
+    }
+    let app = app.with_state(shared_state);
 
     let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
     let listener = TcpListener::bind(addr).await.unwrap();
     println!("Listening on {}", addr);
 
-    axum::serve(listener, app).await.unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    // Spawn a task promoting provisional notes once finalized
+    if let Some(client) = client.clone() {
+        let memdb = memdb.clone();
+        let state = worker_state.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(FINALITY_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(err) = settle_provisional(&client, &memdb, &state).await {
+                    state.sync.record_error(format!("error checking finality: {}", err));
+                }
+            }
+        });
+    }
+
+    // Spawn a task writing periodic snapshots, without notes so decrypted
+    // data never lands on disk
+    if let Ok(dir) = std::env::var(SNAPSHOT_DIR_ENV) {
+        let memdb = memdb.clone();
+        let sync = sync.clone();
+        tokio::spawn(async move {
+            let dir = PathBuf::from(dir);
+            let mut interval = tokio::time::interval(SNAPSHOT_INTERVAL);
+            // the first tick fires right away, before anything is synced
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let snapshot = snapshot::take(&memdb, &sync, false).await;
+                let dir = dir.clone();
+                let written = tokio::task::spawn_blocking(move || {
+                    snapshot::write_rotating(&dir, &snapshot, SNAPSHOT_KEEP)
+                })
+                .await;
+                match written {
+                    Ok(Ok(path)) => println!("wrote snapshot {:?}", path),
+                    Ok(Err(err)) => sync.record_error(format!("error writing snapshot: {}", err)),
+                    Err(err) => sync.record_error(format!("error writing snapshot: {}", err)),
+                }
+            }
+        });
+    }
+
+    // Spawn a task sampling the chain tip
+    if let Some(client) = client.clone() {
+        let sync = sync.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(TIP_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(err) = client.get_tip_slot().await {
+                    sync.record_error(format!("error fetching chain tip: {}", err));
+                }
+            }
+        });
+    }
+
+    // Spawn a task comparing the trees with the chain, resyncing on divergence
+    if let Some(client) = client.clone() {
+        let state = worker_state.clone();
+        tokio::spawn(async move {
+            let mut checker = TreeChecker::new(program_id);
+            let mut interval = tokio::time::interval(ROOT_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(err) = checker.check(&client, &state).await {
+                    state.sync.record_error(format!("error checking tree roots: {}", err));
+                }
+            }
+        });
+    }
+
+    // storage first, the processors after it see what it changed. Without
+    // accounts there is nobody to notify.
+    let pipeline = if public {
+        Pipeline::new()
+            .with(StoreProcessor::keyless(memdb.clone(), refetch_tx.clone()))
+            .with(MetricsProcessor::new(metrics.clone()))
+    } else {
+        Pipeline::new()
+            .with(StoreProcessor::new(memdb.clone(), refetch_tx.clone()))
+            .with(NotifyProcessor::new(memdb.clone(), worker_state.events.clone()))
+            .with(WebhookProcessor::new(memdb.clone(), worker_state.webhooks.clone()))
+            .with(MetricsProcessor::new(metrics.clone()))
+    };
 
     // Process received logs
-    while let Some(logs) = rx.recv().await {
-        for log in logs {
-            if log.contains(&DEPOSIT_EVENT.to_string()) {
-                if let Some(parsed_event) = Event::parse_event(&log) {
-                    if let Ok(decoded) = general_purpose::STANDARD.decode(parsed_event.value) {
-                        let (utxo, _tree_num, _start_position) =
-                            match decrypt_deposit_cipher_text(KEY_PATH.to_string(), decoded) {
-                                Ok(data) => data,
-                                Err(err) => {
-                                    println!("error decrypting ciphertext: {}", err.to_string());
-
-                                    continue;
-                                },
-                            };
-
-                        let mut db = memdb.lock().await;
-                        let index_map = (*db).insert(vec![utxo.utxo_hash()]);
-                        let index = index_map.get(&utxo.utxo_hash()).unwrap();
-                        (*db).insert_utxo(*index, utxo);
-
-                        // update app state
-                        update_index_state(&worker_state, (*db).to_json().data.clone()).await;
+    let mut feed = Feed::new(feed_rx);
+    let mut last_prune = Instant::now();
+    loop {
+        metrics.set_idle(feed.idle());
+        let mut tx_logs = tokio::select! {
+            tx_logs = feed.next() => match tx_logs {
+                Some(tx_logs) => tx_logs,
+                None => break,
+            },
+            Some(import) = imports_rx.recv() => {
+                let imported = apply_import(
+                    import.prepared,
+                    client.as_deref(),
+                    &program_id,
+                    &worker_state,
+                )
+                .await;
+                let _ = import.reply.send(imported);
+                continue;
+            }
+        };
+        metrics.set_idle(false);
+
+        // applying a finalized transaction again changes nothing, only the
+        // overlap of history and the live feed at the cursor needs dedupe
+        if last_prune.elapsed() >= APPLIED_PRUNE_INTERVAL {
+            if let Some(cursor) = sync.cursor() {
+                memdb.write().await.prune_applied(cursor.slot);
+            }
+            last_prune = Instant::now();
+        }
+
+        if let Some(recorder) = &recorder {
+            if let Err(err) = recorder.record(&tx_logs) {
+                sync.record_error(format!("error recording transaction: {}", err));
+            }
+        }
+
+        let account_keys = Arc::new(accounts.snapshot().await);
+
+        let parsed = parse_program_events(&program_id, &tx_logs);
+        for rejected in &parsed.rejected {
+            println!("rejected log line in {}: {}", tx_logs.signature, rejected);
+        }
+        if parsed.failed {
+            println!("skipping failed transaction {}", tx_logs.signature);
+            continue;
+        }
+
+        // a transaction mentioning the program always logs its invocation,
+        // so no logs at all means the node did not return them. Logs cut
+        // short or broken off lose the events past that point.
+        let mut events = parsed.events;
+        if parsed.truncated || parsed.malformed || tx_logs.logs.is_empty() {
+            events = match &client {
+                Some(client) => {
+                    let recovered = recover_from_instructions(
+                        client,
+                        &program_id,
+                        &memdb,
+                        &sync,
+                        &tx_logs,
+                        events,
+                    )
+                    .await;
+                    match recovered {
+                        Ok(events) => events,
+                        // parked, the refetch brings the transaction back
+                        Err(err) => {
+                            sync.record_error(format!(
+                                "parked {} until it can be placed: {}",
+                                tx_logs.signature, err
+                            ));
+                            let _ = refetch_tx.try_send(());
+                            continue;
+                        }
                     }
                 }
-            }
+                None => {
+                    println!("logs of {} are incomplete and no cluster to recover from", tx_logs.signature);
+                    events
+                }
+            };
+        }
 
-            if log.contains(&TRANSFER_EVENT.to_string())
-                || log.contains(&WITHDRAW_EVENT.to_string())
-            {
-                if let Some(parsed_event) = Event::parse_event(&log) {
-                    if let Ok(decoded) = general_purpose::STANDARD.decode(parsed_event.value) {
-                        let (utxos, leafs, _tree_num, _start_position) =
-                            match decrypt_transaction_cipher_text(KEY_PATH.to_string(), decoded) {
-                                Ok(data) => data,
-                                Err(err) => {
-                                    println!("error decrypting ciphertext: {}", err.to_string());
-
-                                    continue
-                                },
-                            };
-
-                        let mut db = memdb.lock().await;
-                        let index_map = (*db).insert(leafs);
-
-                        utxos.iter().for_each(|utxo| {
-                            let index = index_map.get(&utxo.utxo_hash()).unwrap();
-                            (*db).insert_utxo(*index, utxo.clone());
-                        });
-
-                        // update app state
-                        update_index_state(&worker_state, (*db).to_json().data.clone()).await;
+        // history and the live feed overlap, each instruction is applied
+        // once. One applied before an account was registered is tried again
+        // with the keys of the accounts that missed it only.
+        let names: Vec<String> = account_keys.iter().map(|(name, _)| name.clone()).collect();
+        let mut fresh = BTreeSet::new();
+        let mut rescanned = BTreeSet::new();
+        let mut missing = BTreeSet::new();
+        {
+            let db = memdb.read().await;
+            for event in &events {
+                match db.applied_accounts(&tx_logs.signature, event.instruction_index) {
+                    None => {
+                        fresh.insert(event.instruction_index);
                     }
+                    Some(tried) => {
+                        let untried: Vec<&String> =
+                            names.iter().filter(|name| !tried.contains(*name)).collect();
+                        if !untried.is_empty() {
+                            rescanned.insert(event.instruction_index);
+                            missing.extend(untried.into_iter().cloned());
+                        }
+                    }
+                }
+            }
+        }
+        if !events.is_empty() && fresh.is_empty() && rescanned.is_empty() {
+            advance_checkpoint(&memdb, &sync, &tx_logs).await;
+            continue;
+        }
+        let mut applied: Vec<usize> = fresh.union(&rescanned).copied().collect();
+
+        if !tx_logs.finalized {
+            memdb.write().await.begin_provisional(&tx_logs.signature, tx_logs.slot);
+        }
+
+        // live logs carry no block time, look it up once per transaction
+        if tx_logs.block_time.is_none() && !events.is_empty() {
+            if let Some(client) = &client {
+                match client.get_block_time(tx_logs.slot).await {
+                    Ok(block_time) => tx_logs.block_time = Some(block_time),
+                    Err(err) => println!("error fetching block time of slot {}: {}", tx_logs.slot, err),
                 }
             }
+        }
 
-            if log.contains(&NULLIFIERS_EVENT.to_string()) {
-                if let Some(parsed_event) = Event::parse_event(&log) {
-                    if let Ok(decoded) = general_purpose::STANDARD.decode(parsed_event.value) {
-                        let _nullifiers = match get_nullifiers_from_event(decoded) {
-                            Ok(data) => data,
-                            Err(_err) => continue,
-                        };
-                    }
+        let tx_ref = tx_logs.tx_ref();
+        let events: Vec<IndexerEvent> = events
+            .iter()
+            .filter_map(|event| match IndexerEvent::decode(event, &tx_ref) {
+                Ok(event) => Some(event),
+                Err(err) => {
+                    println!("error decoding event of {}: {}", tx_logs.signature, err);
+                    None
+                }
+            })
+            .collect();
+
+        // every event of the transaction tells change apart from incoming
+        // notes, applied before or not
+        let context = TxContext::new(account_keys.clone(), &events);
+        let rescan = context.rescan(Arc::new(
+            account_keys
+                .iter()
+                .filter(|(name, _)| missing.contains(name))
+                .cloned()
+                .collect(),
+        ));
+        for event in &events {
+            let index = event.meta().instruction_index;
+            let context = if fresh.contains(&index) {
+                &context
+            } else if rescanned.contains(&index) {
+                &rescan
+            } else {
+                continue;
+            };
+            if pipeline.process(event, context).await.deferred {
+                applied.retain(|applied| *applied != index);
+            }
+        }
+
+        let trees: BTreeSet<u64> = events
+            .iter()
+            .filter_map(|event| event.commitments())
+            .map(|(tree_number, _, _)| tree_number)
+            .collect();
+        memdb.write().await.mark_applied(
+            &tx_logs.signature,
+            tx_logs.slot,
+            tx_logs.finalized,
+            trees,
+            applied,
+            &names,
+        );
+        metrics.processed_slot(tx_logs.slot);
+        sync.advance(&tx_logs.signature, tx_logs.slot);
+        advance_checkpoint(&memdb, &sync, &tx_logs).await;
+    }
+
+    Ok(())
+}
+
+// replays of history stop at a finalized transaction once no tree misses
+// leaves, a gap means something before it is still missing
+async fn advance_checkpoint(memdb: &RwLock<MemDb>, sync: &SyncStatus, tx_logs: &TransactionLogs) {
+    if tx_logs.finalized && memdb.read().await.trees_with_gaps().is_empty() {
+        sync.set_checkpoint(&tx_logs.signature, tx_logs.slot);
+    }
+}
+
+// endpoints that hold keys or serve an account's notes
+fn account_routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/notes", get(leafs))
+        .route("/accounts", post(register_account))
+        .route("/accounts/{name}", delete(remove_account))
+        .route("/v2/accounts/{account}/notes", get(v2::notes))
+        .route("/v2/accounts/{account}/events", get(v2::events))
+        .route(
+            "/v2/accounts/{account}/webhook",
+            get(webhook::get_webhook)
+                .put(webhook::set_webhook)
+                .delete(webhook::remove_webhook),
+        )
+        .route("/v2/accounts/{account}/webhook/deliveries", get(webhook::deliveries))
+        .route("/v2/accounts/{account}/webhook/replay", post(webhook::replay))
+}
+
+fn rpc_config() -> RpcConfig {
+    let endpoints = match std::env::var(RPC_URLS_ENV) {
+        Ok(urls) => urls
+            .split(',')
+            .map(|url| url.trim().to_string())
+            .filter(|url| !url.is_empty())
+            .collect(),
+        Err(_) => vec![RPC_URL.to_string()],
+    };
+    let millis = |name: &str| env_number(name).map(Duration::from_millis);
+
+    RpcConfig::new(endpoints)
+        .with_rate_limit(env_number(RPC_RATE_LIMIT_ENV))
+        .with_max_retries(env_number(RPC_MAX_RETRIES_ENV))
+        .with_backoff(millis(RPC_INITIAL_BACKOFF_MS_ENV), millis(RPC_MAX_BACKOFF_MS_ENV))
+}
+
+// a numeric setting, unset when missing or not a number
+fn env_number<T: FromStr>(name: &str) -> Option<T> {
+    std::env::var(name).ok().and_then(|value| value.parse().ok())
+}
+
+// verifies an uploaded snapshot against the chain and swaps it in, between
+// two transactions of the ingestion loop
+async fn apply_import(
+    prepared: Prepared,
+    client: Option<&SolanaClient>,
+    program_id: &Pubkey,
+    state: &AppState,
+) -> Result<Option<Cursor>, String> {
+    // a recording has no chain to compare with
+    if let Some(client) = client {
+        let unverified = snapshot::verify_on_chain(&prepared, client, program_id).await?;
+        if !unverified.is_empty() {
+            println!("trees {:?} of the snapshot are behind the chain, unverified", unverified);
+        }
+    }
+    let cursor = snapshot::restore(&state.memdb, &state.sync, &state.metrics, prepared).await;
+
+    // a replay already queued starts from the snapshot too
+    let _ = state.refetch_tx.try_send(());
+
+    Ok(cursor)
+}
+
+// promotes provisional transactions that reached finality and rolls back the
+// ones whose fork was dropped, then replays history to fill what was undone
+async fn settle_provisional(
+    client: &SolanaClient,
+    memdb: &RwLock<MemDb>,
+    state: &Arc<AppState>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let provisional = memdb.read().await.provisional_transactions();
+    if provisional.is_empty() {
+        return Ok(());
+    }
+
+    let signatures: Vec<String> = provisional.iter().map(|(signature, _)| signature.clone()).collect();
+    let finalized_slot = client.get_finalized_slot().await?;
+    let statuses = client.get_signature_statuses(&signatures).await?;
+
+    let mut db = memdb.write().await;
+    let mut rolled_back = false;
+    let mut finalized = vec![];
+    for ((signature, slot), status) in provisional.iter().zip(statuses) {
+        match status {
+            Some(status) if status.satisfies_commitment(CommitmentConfig::finalized()) => {
+                for (account, key) in db.promote(signature) {
+                    let note = match db.note(&account, &key) {
+                        Some(note) => note,
+                        None => continue,
+                    };
+                    // the promoted transaction either created or spent the note
+                    let tx = match &note.spent {
+                        Some(spent_by) if spent_by.signature == *signature => spent_by,
+                        _ => &note.created_by,
+                    };
+                    finalized.push(WebhookPayload::new(NOTE_FINALIZED, &account, &key, note, tx));
                 }
             }
+            // the slot is final but the transaction is not part of it
+            None if *slot <= finalized_slot => {
+                let undone = db.rollback(signature);
+                if !undone.is_empty() {
+                    println!("rolled back transactions from a dropped fork: {:?}", undone);
+                    rolled_back = true;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if rolled_back {
+        let roots = db.roots();
+        state.metrics.set_tree_sizes(&roots);
+        for (tree_number, root, leaf_count) in roots {
+            state.events.root_changed(RootView::new(tree_number, &root, leaf_count)).await;
         }
+        // transactions applied after the dropped one may still be canonical
+        let _ = state.refetch_tx.try_send(());
+    }
+    drop(db);
+
+    for payload in finalized {
+        state.webhooks.notify(payload).await;
     }
 
     Ok(())
 }
 
-async fn update_index_state(state: &Arc<AppState>, new_data: String) {
-    let mut index = state.lock().await;
-    *index = new_data;
+// rebuilds the events of a transaction with incomplete logs from its
+// instruction data. Fails when the instructions cannot be fetched or the
+// chain does not prove where the commitments of an instruction that logged
+// no position landed, the transaction is then left for a refetch.
+async fn recover_from_instructions(
+    client: &SolanaClient,
+    program_id: &Pubkey,
+    memdb: &RwLock<MemDb>,
+    sync: &SyncStatus,
+    tx_logs: &TransactionLogs,
+    logged: Vec<ProgramEvent>,
+) -> Result<Vec<ProgramEvent>, String> {
+    let instructions = match client.get_program_instructions(&tx_logs.signature, program_id).await {
+        Ok(Some(instructions)) => instructions,
+        Ok(None) => return Ok(vec![]), // failed transactions change nothing
+        Err(err) => return Err(format!("error fetching instructions: {}", err)),
+    };
+
+    let recovered = recover_events(instructions, &logged, &tx_logs.signature, tx_logs.slot);
+    for mismatch in &recovered.mismatches {
+        sync.record_error(format!("mismatch in {}: {}", tx_logs.signature, mismatch));
+    }
+
+    let leaves = recovered.unplaced_leaves();
+    // with every position logged there is nothing left to place
+    let (tree_number, start_position) = if leaves.is_empty() {
+        (0, 0)
+    } else {
+        let chain = chain_tree(client, program_id)
+            .await
+            .map_err(|err| format!("error fetching the newest tree: {}", err))?;
+        memdb
+            .read()
+            .await
+            .prove_position(&recovered.logged_batches(), &leaves, Some(&chain))
+            .ok_or(format!("no chain data proves where its {} lost leaves go", leaves.len()))?
+    };
+
+    let events = recovered.place(tree_number, start_position)?;
+    println!(
+        "recovered {} events of {} from instruction data",
+        events.len(),
+        tx_logs.signature
+    );
+
+    Ok(events)
 }
+
+// number, root and leaf count of the tree the program appends to, at
+// finalized commitment
+async fn chain_tree(
+    client: &SolanaClient,
+    program_id: &Pubkey,
+) -> Result<(u64, Vec<u8>, u64), Box<dyn Error + Send + Sync>> {
+    let tree_number = client.get_current_tree_number(program_id).await?;
+    let (root, leaf_count) = client.get_tree_state(program_id, tree_number).await?;
+
+    Ok((tree_number, root, leaf_count))
+}
+
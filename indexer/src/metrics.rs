@@ -0,0 +1,213 @@
+use std::{
+    future::Future,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Instant,
+};
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+/// Counters and gauges describing how well the indexer keeps up with the
+/// chain, served in the Prometheus text format on `/metrics`.
+pub struct Metrics {
+    registry: Registry,
+    pub last_processed_slot: IntGauge,
+    pub chain_tip_slot: IntGauge,
+    pub slot_lag: IntGauge,
+    pub events_processed: IntCounterVec, // by event type
+    pub decryption_attempts: IntCounter,
+    pub decryption_successes: IntCounter,
+    pub decryption_failures: IntCounter,
+    pub rpc_calls: IntCounterVec, // by method and outcome
+    pub rpc_latency: HistogramVec, // seconds, by method
+    pub websocket_reconnects: IntCounter,
+    pub tree_size: IntGaugeVec, // leaves, by tree number
+    pub tree_divergences: IntCounterVec, // by tree number
+    // nothing waits to be processed, a quiet program is not lagging
+    idle: AtomicBool,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new_custom(Some("indexer".to_string()), None)?;
+
+        let last_processed_slot =
+            IntGauge::new("last_processed_slot", "Slot of the last processed transaction")?;
+        let chain_tip_slot = IntGauge::new("chain_tip_slot", "Latest confirmed slot of the cluster")?;
+        let slot_lag = IntGauge::new(
+            "slot_lag",
+            "Slots between the chain tip and the last processed transaction, 0 when nothing waits",
+        )?;
+        let events_processed = IntCounterVec::new(
+            Opts::new("events_processed_total", "Program events processed"),
+            &["type"],
+        )?;
+        let decryption_attempts = IntCounter::new(
+            "decryption_attempts_total",
+            "Trial decryptions of note ciphertexts, one per account and ciphertext",
+        )?;
+        let decryption_successes = IntCounter::new(
+            "decryption_successes_total",
+            "Trial decryptions that yielded a note",
+        )?;
+        let decryption_failures = IntCounter::new(
+            "decryption_failures_total",
+            "Trial decryptions that did not yield a note",
+        )?;
+        let rpc_calls = IntCounterVec::new(
+            Opts::new("rpc_calls_total", "RPC requests sent to the cluster"),
+            &["method", "status"],
+        )?;
+        let rpc_latency = HistogramVec::new(
+            HistogramOpts::new("rpc_latency_seconds", "RPC request latency"),
+            &["method"],
+        )?;
+        let websocket_reconnects = IntCounter::new(
+            "websocket_reconnects_total",
+            "Times the log subscription was re-established",
+        )?;
+        let tree_size = IntGaugeVec::new(
+            Opts::new("tree_size", "Leaves in each commitment tree"),
+            &["tree_number"],
+        )?;
+
+        let tree_divergences = IntCounterVec::new(
+            Opts::new(
+                "tree_divergences_total",
+                "Times a tree disagreed with its on-chain account and was resynced",
+            ),
+            &["tree_number"],
+        )?;
+
+        registry.register(Box::new(last_processed_slot.clone()))?;
+        registry.register(Box::new(chain_tip_slot.clone()))?;
+        registry.register(Box::new(slot_lag.clone()))?;
+        registry.register(Box::new(events_processed.clone()))?;
+        registry.register(Box::new(decryption_attempts.clone()))?;
+        registry.register(Box::new(decryption_successes.clone()))?;
+        registry.register(Box::new(decryption_failures.clone()))?;
+        registry.register(Box::new(rpc_calls.clone()))?;
+        registry.register(Box::new(rpc_latency.clone()))?;
+        registry.register(Box::new(websocket_reconnects.clone()))?;
+        registry.register(Box::new(tree_size.clone()))?;
+        registry.register(Box::new(tree_divergences.clone()))?;
+
+        Ok(Metrics {
+            registry,
+            last_processed_slot,
+            chain_tip_slot,
+            slot_lag,
+            events_processed,
+            decryption_attempts,
+            decryption_successes,
+            decryption_failures,
+            rpc_calls,
+            rpc_latency,
+            websocket_reconnects,
+            tree_size,
+            tree_divergences,
+            idle: AtomicBool::new(false),
+        })
+    }
+
+    pub fn event_processed(&self, event_type: &str) {
+        self.events_processed.with_label_values(&[event_type]).inc();
+    }
+
+    pub fn decrypted(&self, attempts: u64, successes: u64) {
+        self.decryption_attempts.inc_by(attempts);
+        self.decryption_successes.inc_by(successes);
+        self.decryption_failures.inc_by(attempts.saturating_sub(successes));
+    }
+
+    pub fn processed_slot(&self, slot: u64) {
+        // historical replays must not move the cursor backwards
+        if slot as i64 > self.last_processed_slot.get() {
+            self.last_processed_slot.set(slot as i64);
+        }
+        self.update_lag();
+    }
+
+    pub fn chain_tip(&self, slot: u64) {
+        self.chain_tip_slot.set(slot as i64);
+        self.update_lag();
+    }
+
+    /// Whether the ingestion loop has nothing left to process. The lag is
+    /// only measured while it does.
+    pub fn set_idle(&self, idle: bool) {
+        self.idle.store(idle, Ordering::Relaxed);
+        self.update_lag();
+    }
+
+    fn update_lag(&self) {
+        let lag = if self.idle.load(Ordering::Relaxed) {
+            0
+        } else {
+            self.chain_tip_slot.get() - self.last_processed_slot.get()
+        };
+        self.slot_lag.set(lag.max(0));
+    }
+
+    /// Sets the tree sizes from `MemDb::roots`, dropping the gauges of trees
+    /// that no longer exist.
+    pub fn set_tree_sizes(&self, roots: &[(u64, Vec<u8>, u64)]) {
+        self.tree_size.reset();
+        for (tree_number, _, leaf_count) in roots {
+            self.tree_size
+                .with_label_values(&[&tree_number.to_string()])
+                .set(*leaf_count as i64);
+        }
+    }
+
+    pub fn tree_diverged(&self, tree_number: u64) {
+        self.tree_divergences
+            .with_label_values(&[&tree_number.to_string()])
+            .inc();
+    }
+
+    /// Times an RPC request and counts it under `method`.
+    pub async fn observe_rpc<T, E, F>(&self, method: &str, request: F) -> Result<T, E>
+    where
+        F: Future<Output = Result<T, E>>,
+    {
+        let started = Instant::now();
+        let result = request.await;
+
+        self.rpc_latency
+            .with_label_values(&[method])
+            .observe(started.elapsed().as_secs_f64());
+        let status = if result.is_ok() { "ok" } else { "error" };
+        self.rpc_calls.with_label_values(&[method, status]).inc();
+
+        result
+    }
+
+    pub fn encode(&self) -> Result<String, String> {
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(|err| format!("cannot encode metrics: {}", err))?;
+
+        String::from_utf8(buffer).map_err(|err| format!("invalid metrics encoding: {}", err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_sizes_of_removed_trees() {
+        let metrics = Metrics::new().unwrap();
+        metrics.set_tree_sizes(&[(0, vec![], 8), (1, vec![], 3)]);
+        // a rollback shrank tree 0 and removed tree 1
+        metrics.set_tree_sizes(&[(0, vec![], 5)]);
+
+        let encoded = metrics.encode().unwrap();
+        assert!(encoded.contains("indexer_tree_size{tree_number=\"0\"} 5"));
+        assert!(!encoded.contains("tree_number=\"1\""));
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::VecDeque;
+
+use serde::Serialize;
+use tokio::sync::{Mutex, broadcast};
+
+use crate::api_handler::v2::{NoteView, RootView, TxView};
+
+// events kept around for clients resuming from an older sequence number
+const HISTORY_SIZE: usize = 10_000;
+const CHANNEL_SIZE: usize = 1024;
+
+pub const NOTE_RECEIVED: &str = "note_received";
+pub const NOTE_SPENT: &str = "note_spent";
+pub const ROOT_CHANGED: &str = "root_changed";
+
+#[derive(Serialize)]
+pub struct NoteSpent {
+    pub tree_number: u64,
+    pub leaf_index: u64,
+    pub spent_by: TxView,
+}
+
+/// A change applied by the ingestion loop, numbered in the order it happened.
+#[derive(Clone, Debug)]
+pub struct StreamEvent {
+    pub seq: u64,
+    pub kind: &'static str,
+    // account the event belongs to, `None` for public events like new roots
+    pub account: Option<String>,
+    pub data: String, // json payload
+}
+
+impl StreamEvent {
+    pub fn visible_to(&self, account: &str) -> bool {
+        match &self.account {
+            Some(owner) => owner == account,
+            None => true,
+        }
+    }
+}
+
+struct History {
+    next_seq: u64,
+    events: VecDeque<StreamEvent>,
+}
+
+/// Fans applied changes out to stream subscribers and keeps a bounded
+/// history so they can resume after a disconnect.
+pub struct EventHub {
+    history: Mutex<History>,
+    sender: broadcast::Sender<StreamEvent>,
+}
+
+impl EventHub {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_SIZE);
+
+        EventHub {
+            history: Mutex::new(History {
+                next_seq: 1,
+                events: VecDeque::new(),
+            }),
+            sender,
+        }
+    }
+
+    pub async fn note_received(&self, account: &str, note: NoteView) {
+        self.publish(NOTE_RECEIVED, Some(account.to_string()), &note).await;
+    }
+
+    pub async fn note_spent(&self, account: &str, spent: NoteSpent) {
+        self.publish(NOTE_SPENT, Some(account.to_string()), &spent).await;
+    }
+
+    pub async fn root_changed(&self, root: RootView) {
+        self.publish(ROOT_CHANGED, None, &root).await;
+    }
+
+    async fn publish<T: Serialize>(&self, kind: &'static str, account: Option<String>, payload: &T) {
+        let data = match serde_json::to_string(payload) {
+            Ok(data) => data,
+            Err(err) => return println!("error serializing {} event: {}", kind, err),
+        };
+
+        let mut history = self.history.lock().await;
+        let event = StreamEvent {
+            seq: history.next_seq,
+            kind,
+            account,
+            data,
+        };
+        history.next_seq += 1;
+
+        history.events.push_back(event.clone());
+        if history.events.len() > HISTORY_SIZE {
+            history.events.pop_front();
+        }
+
+        // no subscribers is fine, the event stays in the history
+        let _ = self.sender.send(event);
+    }
+
+    /// Returns the retained events after `since` and a receiver for every
+    /// later one, with nothing lost or repeated in between. Fails when
+    /// events after `since` were already dropped from the history.
+    pub async fn subscribe(
+        &self,
+        since: Option<u64>,
+    ) -> Result<(Vec<StreamEvent>, broadcast::Receiver<StreamEvent>), String> {
+        // holding the lock keeps publish from slipping an event in between
+        let history = self.history.lock().await;
+        let receiver = self.sender.subscribe();
+
+        let since = match since {
+            Some(since) => since,
+            None => return Ok((vec![], receiver)),
+        };
+
+        let oldest = history.events.front().map(|event| event.seq).unwrap_or(history.next_seq);
+        if since + 1 < oldest {
+            return Err(format!(
+                "events after {} are no longer retained, oldest is {}",
+                since, oldest
+            ));
+        }
+
+        let backlog = history
+            .events
+            .iter()
+            .filter(|event| event.seq > since)
+            .cloned()
+            .collect();
+
+        Ok((backlog, receiver))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spent(leaf_index: u64) -> NoteSpent {
+        NoteSpent {
+            tree_number: 0,
+            leaf_index,
+            spent_by: TxView {
+                signature: "spend".to_string(),
+                slot: 12,
+                finalized: false,
+                block_time: None,
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn resumes_without_losing_or_repeating_events() {
+        let hub = EventHub::new();
+        hub.note_spent("alice", spent(0)).await;
+        hub.root_changed(RootView::new(0, &[1; 32], 1)).await;
+
+        let (backlog, mut receiver) = hub.subscribe(Some(1)).await.unwrap();
+        let seqs: Vec<u64> = backlog.iter().map(|event| event.seq).collect();
+        assert_eq!(seqs, vec![2]);
+        assert_eq!(backlog[0].kind, ROOT_CHANGED);
+
+        hub.note_spent("bob", spent(1)).await;
+        let live = receiver.recv().await.unwrap();
+        assert_eq!((live.seq, live.kind), (3, NOTE_SPENT));
+        assert!(live.visible_to("bob"));
+        assert!(!live.visible_to("alice"));
+        // roots are public
+        assert!(backlog[0].visible_to("alice"));
+    }
+
+    #[tokio::test]
+    async fn refuses_to_resume_past_the_history() {
+        let hub = EventHub::new();
+        for leaf_index in 0..=HISTORY_SIZE as u64 {
+            hub.note_spent("alice", spent(leaf_index)).await;
+        }
+
+        // event 1 was dropped, resuming after it is fine
+        assert!(hub.subscribe(Some(0)).await.is_err());
+        assert!(hub.subscribe(Some(1)).await.is_ok());
+        assert!(hub.subscribe(None).await.is_ok_and(|(backlog, _)| backlog.is_empty()));
+    }
+}
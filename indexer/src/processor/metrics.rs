@@ -0,0 +1,39 @@
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+
+use super::{Applied, EventProcessor, TxContext};
+use crate::{event::IndexerEvent, metrics::Metrics};
+
+/// Counts events and trial decryptions.
+pub struct MetricsProcessor {
+    metrics: Arc<Metrics>,
+}
+
+impl MetricsProcessor {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        MetricsProcessor { metrics }
+    }
+}
+
+impl EventProcessor for MetricsProcessor {
+    fn process<'a>(
+        &'a self,
+        event: &'a IndexerEvent,
+        tx: &'a TxContext,
+        applied: &'a mut Applied,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        // counted when the instruction is applied again
+        if applied.deferred {
+            return Box::pin(async { Ok(()) });
+        }
+        // a rescan only tries more keys on an event counted already
+        if !tx.rescan {
+            self.metrics.event_processed(event.kind().as_str());
+        }
+        self.metrics
+            .decrypted(applied.decryption_attempts, applied.decryption_successes);
+
+        Box::pin(async { Ok(()) })
+    }
+}
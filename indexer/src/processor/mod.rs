@@ -0,0 +1,179 @@
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+
+use crate::{account::AccountKeys, client::NoteKey, event::IndexerEvent};
+
+pub mod metrics;
+pub mod notify;
+pub mod store;
+pub mod webhook;
+
+/// The transaction an event belongs to, as far as processors care.
+pub struct TxContext {
+    // accounts registered when the transaction was picked up
+    pub account_keys: Arc<Vec<(String, AccountKeys)>>,
+    // every nullifier the transaction reveals, telling change apart from
+    // incoming notes
+    pub spent: Vec<Vec<u8>>,
+    // the events were applied before, only the notes of `account_keys` are
+    // looked for
+    pub rescan: bool,
+}
+
+impl TxContext {
+    pub fn new(account_keys: Arc<Vec<(String, AccountKeys)>>, events: &[IndexerEvent]) -> Self {
+        let spent = events
+            .iter()
+            .flat_map(|event| event.nullifiers().iter().cloned())
+            .collect();
+
+        TxContext {
+            account_keys,
+            spent,
+            rescan: false,
+        }
+    }
+
+    /// The same transaction tried again with the keys of accounts
+    /// registered after it was applied.
+    pub fn rescan(&self, account_keys: Arc<Vec<(String, AccountKeys)>>) -> Self {
+        TxContext {
+            account_keys,
+            spent: self.spent.clone(),
+            rescan: true,
+        }
+    }
+}
+
+/// What an event changed, filled in by the processor that changed it for
+/// the ones after it.
+#[derive(Default)]
+pub struct Applied {
+    // notes stored for the first time
+    pub received: Vec<(String, NoteKey)>,
+    pub spent: Vec<(String, NoteKey)>,
+    pub grown_tree: Option<u64>,
+    pub decryption_attempts: u64,
+    pub decryption_successes: u64,
+    // the leaves arrived ahead of the tree, the instruction is applied again
+    // after the refetch
+    pub deferred: bool,
+}
+
+/// A consumer of indexer events.
+pub trait EventProcessor: Send + Sync {
+    fn process<'a>(
+        &'a self,
+        event: &'a IndexerEvent,
+        tx: &'a TxContext,
+        applied: &'a mut Applied,
+    ) -> BoxFuture<'a, Result<(), String>>;
+}
+
+/// Hands every event to its processors in the order they were added. A
+/// failing processor is logged and does not stop the ones after it.
+pub struct Pipeline {
+    processors: Vec<Box<dyn EventProcessor>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Pipeline { processors: vec![] }
+    }
+
+    pub fn with(mut self, processor: impl EventProcessor + 'static) -> Self {
+        self.processors.push(Box::new(processor));
+        self
+    }
+
+    /// Runs every processor on `event` and returns what they changed.
+    pub async fn process(&self, event: &IndexerEvent, tx: &TxContext) -> Applied {
+        let mut applied = Applied::default();
+
+        for processor in &self.processors {
+            if let Err(err) = processor.process(event, tx, &mut applied).await {
+                println!(
+                    "error processing {} event of {}: {}",
+                    event.kind().as_str(),
+                    event.meta().tx.signature,
+                    err
+                );
+            }
+        }
+
+        applied
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use darksol::NullifierEvent;
+
+    use super::*;
+    use crate::{client::TxRef, event::EventMeta};
+
+    // records the decryption attempts it was handed and adds one
+    struct Step {
+        name: &'static str,
+        fails: bool,
+        seen: Arc<Mutex<Vec<(&'static str, u64)>>>,
+    }
+
+    impl EventProcessor for Step {
+        fn process<'a>(
+            &'a self,
+            _event: &'a IndexerEvent,
+            _tx: &'a TxContext,
+            applied: &'a mut Applied,
+        ) -> BoxFuture<'a, Result<(), String>> {
+            Box::pin(async move {
+                self.seen.lock().unwrap().push((self.name, applied.decryption_attempts));
+                applied.decryption_attempts += 1;
+                if self.fails { Err("failed".to_string()) } else { Ok(()) }
+            })
+        }
+    }
+
+    fn nullifiers(nullifiers: Vec<Vec<u8>>) -> IndexerEvent {
+        IndexerEvent::Nullifiers {
+            meta: EventMeta {
+                tx: TxRef {
+                    signature: "signature".to_string(),
+                    slot: 42,
+                    finalized: true,
+                    block_time: None,
+                },
+                instruction_index: 0,
+            },
+            event: NullifierEvent { nullifiers },
+        }
+    }
+
+    #[tokio::test]
+    async fn runs_processors_in_order_past_failures() {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let step = |name, fails| Step {
+            name,
+            fails,
+            seen: seen.clone(),
+        };
+        let pipeline = Pipeline::new()
+            .with(step("store", false))
+            .with(step("notify", true))
+            .with(step("metrics", false));
+
+        let events = vec![nullifiers(vec![vec![1; 32], vec![2; 32]])];
+        let context = TxContext::new(Arc::new(vec![]), &events);
+        let applied = pipeline.process(&events[0], &context).await;
+
+        assert_eq!(context.spent, vec![vec![1; 32], vec![2; 32]]);
+        assert_eq!(applied.decryption_attempts, 3);
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![("store", 0), ("notify", 1), ("metrics", 2)]
+        );
+    }
+}
@@ -0,0 +1,64 @@
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use tokio::sync::RwLock;
+
+use super::{Applied, EventProcessor, TxContext};
+use crate::{
+    api_handler::v2::{NoteView, RootView, TxView},
+    event::IndexerEvent,
+    notifier::{EventHub, NoteSpent},
+    storage::db::memdb::MemDb,
+};
+
+/// Pushes what an event changed to stream subscribers.
+pub struct NotifyProcessor {
+    memdb: Arc<RwLock<MemDb>>,
+    events: Arc<EventHub>,
+}
+
+impl NotifyProcessor {
+    pub fn new(memdb: Arc<RwLock<MemDb>>, events: Arc<EventHub>) -> Self {
+        NotifyProcessor { memdb, events }
+    }
+
+    async fn publish(&self, event: &IndexerEvent, applied: &Applied) {
+        let db = self.memdb.read().await;
+
+        for (account, key) in &applied.received {
+            if let Some(note) = db.note(account, key) {
+                self.events.note_received(account, NoteView::new(key, note)).await;
+            }
+        }
+
+        for (account, key) in &applied.spent {
+            let spent = NoteSpent {
+                tree_number: key.tree_number,
+                leaf_index: key.leaf_index,
+                spent_by: TxView::from(&event.meta().tx),
+            };
+            self.events.note_spent(account, spent).await;
+        }
+
+        if let Some(tree_number) = applied.grown_tree {
+            let tree = db.roots().into_iter().find(|(number, _, _)| *number == tree_number);
+            if let Some((tree_number, root, leaf_count)) = tree {
+                self.events.root_changed(RootView::new(tree_number, &root, leaf_count)).await;
+            }
+        }
+    }
+}
+
+impl EventProcessor for NotifyProcessor {
+    fn process<'a>(
+        &'a self,
+        event: &'a IndexerEvent,
+        _tx: &'a TxContext,
+        applied: &'a mut Applied,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            self.publish(event, applied).await;
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,293 @@
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use tokio::sync::{RwLock, mpsc};
+use veil_types::generate_nullifier;
+
+use super::{Applied, EventProcessor, TxContext};
+use crate::{
+    account::AccountKeys,
+    client::NoteKind,
+    event::{
+        DecryptedNote, IndexerEvent, decrypt_deposit_cipher_text, decrypt_transaction_cipher_text,
+    },
+    storage::db::memdb::{LeafPlacement, MemDb},
+};
+
+/// Places commitments in their trees, records nullifiers, stores the notes
+/// registered accounts can decrypt and marks spent notes.
+pub struct StoreProcessor {
+    memdb: Arc<RwLock<MemDb>>,
+    refetch_tx: mpsc::Sender<()>,
+    // off in public mode, where no keys are held
+    decrypt: bool,
+}
+
+impl StoreProcessor {
+    pub fn new(memdb: Arc<RwLock<MemDb>>, refetch_tx: mpsc::Sender<()>) -> Self {
+        StoreProcessor {
+            memdb,
+            refetch_tx,
+            decrypt: true,
+        }
+    }
+
+    /// Tracks trees and nullifiers only, without trying any account keys.
+    pub fn keyless(memdb: Arc<RwLock<MemDb>>, refetch_tx: mpsc::Sender<()>) -> Self {
+        StoreProcessor {
+            memdb,
+            refetch_tx,
+            decrypt: false,
+        }
+    }
+
+    async fn apply(
+        &self,
+        event: &IndexerEvent,
+        tx: &TxContext,
+        applied: &mut Applied,
+    ) -> Result<(), String> {
+        let keys = &tx.account_keys;
+        let created_by = &event.meta().tx;
+
+        if let IndexerEvent::Nullifiers { event: nullifiers, .. } = event {
+            // a rescan only looks for notes, the nullifiers are known already
+            if tx.rescan {
+                return Ok(());
+            }
+            let mut db = self.memdb.write().await;
+            applied.spent = db.insert_nullifiers(nullifiers.nullifiers.clone(), created_by.clone());
+            return Ok(());
+        }
+
+        let (notes, kind) = if self.decrypt {
+            decrypt(event, keys, applied).await?
+        } else {
+            (vec![], None)
+        };
+        applied.decryption_successes = notes.len() as u64;
+
+        let mut db = self.memdb.write().await;
+
+        // leaves go first, a note is only stored where the tree holds its
+        // commitment. A rescan finds them placed already.
+        let commitments = event.commitments().filter(|_| !tx.rescan);
+        if let Some((tree_number, start_position, leafs)) = commitments {
+            match self.apply_leafs(&mut db, tree_number, start_position, leafs, created_by.slot) {
+                Some(LeafPlacement::Appended) => applied.grown_tree = Some(tree_number),
+                // the refetch replays the instruction once the gap is filled
+                Some(LeafPlacement::Gap { .. }) => {
+                    applied.deferred = true;
+                    return Ok(());
+                }
+                Some(LeafPlacement::Duplicate) | None => {}
+            }
+        }
+
+        for DecryptedNote { account, key, utxo } in notes {
+            if !db.holds(&key, &utxo.utxo_hash()) {
+                println!(
+                    "note of {} at {}:{} does not match the tree, skipped",
+                    account, key.tree_number, key.leaf_index
+                );
+                continue;
+            }
+            let nullifier = note_nullifier(keys, &account, key.leaf_index)?;
+            let kind = kind.unwrap_or_else(|| {
+                if db.spends_from(&account, &tx.spent) {
+                    NoteKind::Change
+                } else {
+                    NoteKind::Incoming
+                }
+            });
+            if db.insert_note(&account, key, utxo, nullifier, kind, created_by.clone()) {
+                applied.received.push((account, key));
+            }
+        }
+
+        Ok(())
+    }
+
+    // places the leaves of an event at their on-chain position and asks the
+    // historical task to refetch when the event arrived ahead of missing
+    // leaves. Returns None when the leaves conflict with the tree.
+    fn apply_leafs(
+        &self,
+        db: &mut MemDb,
+        tree_number: u64,
+        start_position: u64,
+        leafs: Vec<Vec<u8>>,
+        slot: u64,
+    ) -> Option<LeafPlacement> {
+        match db.insert(tree_number, start_position, leafs, slot) {
+            Ok(LeafPlacement::Gap { next_leaf_index }) => {
+                println!(
+                    "gap in tree {}: expected leaf {} but event starts at {}, refetching",
+                    tree_number, next_leaf_index, start_position
+                );
+                // a refetch already queued covers this gap too
+                let _ = self.refetch_tx.try_send(());
+                Some(LeafPlacement::Gap { next_leaf_index })
+            }
+            Ok(placement) => Some(placement),
+            Err(err) => {
+                println!("error inserting leaves into tree {}: {}", tree_number, err);
+                None
+            }
+        }
+    }
+}
+
+impl EventProcessor for StoreProcessor {
+    fn process<'a>(
+        &'a self,
+        event: &'a IndexerEvent,
+        tx: &'a TxContext,
+        applied: &'a mut Applied,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(self.apply(event, tx, applied))
+    }
+}
+
+// decrypts the notes an event created for registered accounts, with the kind
+// they have whatever the account. Runs on the blocking pool, which fans out
+// to rayon, so the async workers stay free on any runtime flavor.
+async fn decrypt(
+    event: &IndexerEvent,
+    keys: &Arc<Vec<(String, AccountKeys)>>,
+    applied: &mut Applied,
+) -> Result<(Vec<DecryptedNote>, Option<NoteKind>), String> {
+    let accounts = keys.clone();
+
+    match event {
+        IndexerEvent::Deposit { event: deposit, .. } => {
+            let deposit = deposit.clone();
+            let notes =
+                run_blocking(move || Ok(decrypt_deposit_cipher_text(&accounts, &deposit))).await?;
+            applied.decryption_attempts = keys.len() as u64;
+            Ok((notes, Some(NoteKind::Deposit)))
+        }
+        IndexerEvent::Transfer { event: transaction, .. }
+        | IndexerEvent::Withdraw { event: transaction, .. } => {
+            let attempts = keys.len() * transaction.commitments.len();
+            let transaction = transaction.clone();
+            let notes =
+                run_blocking(move || decrypt_transaction_cipher_text(&accounts, &transaction))
+                    .await?;
+            applied.decryption_attempts = attempts as u64;
+            // the only notes a withdraw creates go back to the sender
+            let kind = matches!(event, IndexerEvent::Withdraw { .. }).then_some(NoteKind::Change);
+            Ok((notes, kind))
+        }
+        IndexerEvent::Nullifiers { .. } => Ok((vec![], None)),
+    }
+}
+
+async fn run_blocking<F>(decrypt: F) -> Result<Vec<DecryptedNote>, String>
+where
+    F: FnOnce() -> Result<Vec<DecryptedNote>, String> + Send + 'static,
+{
+    tokio::task::spawn_blocking(decrypt)
+        .await
+        .map_err(|err| format!("decryption task failed: {}", err))?
+}
+
+// nullifier an account reveals when spending the note at `leaf_index`
+fn note_nullifier(
+    account_keys: &[(String, AccountKeys)],
+    account: &str,
+    leaf_index: u64,
+) -> Result<Vec<u8>, String> {
+    let (_, keys) = account_keys
+        .iter()
+        .find(|(name, _)| name == account)
+        .ok_or(format!("no keys for account {}", account))?;
+
+    Ok(generate_nullifier(keys.viewing_key.clone(), leaf_index))
+}
+
+#[cfg(test)]
+mod tests {
+    use darksol::{NullifierEvent, TransactionEvent};
+
+    use super::*;
+    use crate::{client::TxRef, event::EventMeta};
+
+    fn leaf(value: u8) -> Vec<u8> {
+        let mut leaf = vec![0; 32];
+        leaf[31] = value;
+        leaf
+    }
+
+    fn meta() -> EventMeta {
+        EventMeta {
+            tx: TxRef {
+                signature: "signature".to_string(),
+                slot: 42,
+                finalized: true,
+                block_time: None,
+            },
+            instruction_index: 0,
+        }
+    }
+
+    fn transfer(start_position: u64, commitments: Vec<Vec<u8>>) -> IndexerEvent {
+        IndexerEvent::Transfer {
+            meta: meta(),
+            event: Arc::new(TransactionEvent {
+                commitments,
+                commitment_cipher_text: vec![],
+                tree_number: 0,
+                start_position,
+            }),
+        }
+    }
+
+    #[test]
+    fn nullifier_needs_the_accounts_keys() {
+        let keys = AccountKeys::new(vec![1; 32], vec![2; 32]).unwrap();
+        let accounts = vec![("alice".to_string(), keys.clone())];
+
+        assert_eq!(
+            note_nullifier(&accounts, "alice", 3),
+            Ok(generate_nullifier(keys.viewing_key, 3))
+        );
+        assert!(note_nullifier(&accounts, "bob", 3).is_err());
+    }
+
+    #[tokio::test]
+    async fn tracks_trees_and_nullifiers_without_keys() {
+        let memdb = Arc::new(RwLock::new(MemDb::new()));
+        let (refetch_tx, mut refetch_rx) = mpsc::channel(1);
+        let store = StoreProcessor::keyless(memdb.clone(), refetch_tx);
+        // keys handed in anyway are never tried
+        let keys = AccountKeys::new(vec![1; 32], vec![2; 32]).unwrap();
+        let events = vec![
+            transfer(0, vec![leaf(1), leaf(2)]),
+            IndexerEvent::Nullifiers {
+                meta: meta(),
+                event: NullifierEvent { nullifiers: vec![vec![7; 32]] },
+            },
+        ];
+        let tx = TxContext::new(Arc::new(vec![("alice".to_string(), keys)]), &events);
+
+        let mut applied = Applied::default();
+        store.process(&events[0], &tx, &mut applied).await.unwrap();
+        assert_eq!(applied.grown_tree, Some(0));
+        assert_eq!(applied.decryption_attempts, 0);
+        store.process(&events[1], &tx, &mut Applied::default()).await.unwrap();
+
+        let db = memdb.read().await;
+        assert_eq!(db.tree(0).unwrap().next_leaf_index(), 2);
+        assert!(db.nullifier(&[7; 32]).is_some());
+        assert!(db.notes("alice").is_none());
+        drop(db);
+
+        // leaves ahead of the tree wait for the refetch
+        let mut applied = Applied::default();
+        store.process(&transfer(5, vec![leaf(3)]), &tx, &mut applied).await.unwrap();
+        assert!(applied.deferred);
+        assert!(refetch_rx.try_recv().is_ok());
+        assert_eq!(memdb.read().await.tree(0).unwrap().next_leaf_index(), 2);
+    }
+}
@@ -0,0 +1,71 @@
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use tokio::sync::RwLock;
+
+use super::{Applied, EventProcessor, TxContext};
+use crate::{
+    event::IndexerEvent,
+    notifier::{NOTE_RECEIVED, NOTE_SPENT},
+    storage::db::memdb::MemDb,
+    webhook::{WebhookPayload, Webhooks},
+};
+
+/// Tells accounts' webhooks about notes they received or spent.
+pub struct WebhookProcessor {
+    memdb: Arc<RwLock<MemDb>>,
+    webhooks: Arc<Webhooks>,
+}
+
+impl WebhookProcessor {
+    pub fn new(memdb: Arc<RwLock<MemDb>>, webhooks: Arc<Webhooks>) -> Self {
+        WebhookProcessor { memdb, webhooks }
+    }
+
+    async fn notify(&self, event: &IndexerEvent, applied: &Applied) {
+        let mut payloads = vec![];
+        {
+            let db = self.memdb.read().await;
+            for (account, key) in &applied.received {
+                if let Some(note) = db.note(account, key) {
+                    payloads.push(WebhookPayload::new(
+                        NOTE_RECEIVED,
+                        account,
+                        key,
+                        note,
+                        &note.created_by,
+                    ));
+                }
+            }
+            for (account, key) in &applied.spent {
+                if let Some(note) = db.note(account, key) {
+                    payloads.push(WebhookPayload::new(
+                        NOTE_SPENT,
+                        account,
+                        key,
+                        note,
+                        &event.meta().tx,
+                    ));
+                }
+            }
+        }
+
+        for payload in payloads {
+            self.webhooks.notify(payload).await;
+        }
+    }
+}
+
+impl EventProcessor for WebhookProcessor {
+    fn process<'a>(
+        &'a self,
+        event: &'a IndexerEvent,
+        _tx: &'a TxContext,
+        applied: &'a mut Applied,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            self.notify(event, applied).await;
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,242 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use sha2::{Digest, Sha256};
+use solana_sdk::pubkey::Pubkey;
+use tokio::sync::{RwLock, oneshot};
+
+use crate::{
+    client::solana::SolanaClient,
+    health::{Cursor, SyncStatus},
+    metrics::Metrics,
+    storage::db::memdb::{MemDb, MemDbSnapshot},
+};
+
+pub const SNAPSHOT_VERSION: u32 = 5;
+
+// file layout: magic | version (u32 le) | sha256 of the body | borsh body
+const MAGIC: &[u8; 8] = b"VEILSNAP";
+const HEADER_LENGTH: usize = 8 + 4 + 32;
+const FILE_PREFIX: &str = "snapshot-";
+const FILE_EXTENSION: &str = "bin";
+
+/// Indexer state at a point of the chain, enough to resume live sync from
+/// `cursor` without replaying the history before it.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct Snapshot {
+    pub cursor: Option<Cursor>,
+    pub state: MemDbSnapshot,
+}
+
+impl Snapshot {
+    pub fn encode(&self) -> Result<Vec<u8>, String> {
+        let body = borsh::to_vec(self).map_err(|err| format!("cannot encode snapshot: {}", err))?;
+
+        let mut bytes = Vec::with_capacity(HEADER_LENGTH + body.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&Sha256::digest(&body));
+        bytes.extend_from_slice(&body);
+
+        Ok(bytes)
+    }
+
+    /// Parses a snapshot file, rejecting unknown versions and corrupted
+    /// bodies.
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < HEADER_LENGTH || &bytes[..8] != MAGIC {
+            return Err("not a snapshot file".to_string());
+        }
+
+        let version = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+        if version != SNAPSHOT_VERSION {
+            return Err(format!(
+                "unsupported snapshot version {}, expected {}",
+                version, SNAPSHOT_VERSION
+            ));
+        }
+
+        let body = &bytes[HEADER_LENGTH..];
+        if Sha256::digest(body).as_slice() != &bytes[12..HEADER_LENGTH] {
+            return Err("snapshot checksum mismatch, the file is corrupted".to_string());
+        }
+
+        Snapshot::try_from_slice(body).map_err(|err| format!("cannot decode snapshot: {}", err))
+    }
+}
+
+/// Captures the current state together with the sync cursor.
+pub async fn take(memdb: &RwLock<MemDb>, sync: &SyncStatus, include_notes: bool) -> Snapshot {
+    // a cursor trailing the state only replays transactions that are
+    // already applied, which is harmless
+    let db = memdb.read().await;
+
+    Snapshot {
+        cursor: sync.cursor(),
+        state: db.snapshot(include_notes),
+    }
+}
+
+/// A decoded snapshot whose trees were rebuilt, ready to replace the state.
+pub struct Prepared {
+    cursor: Option<Cursor>,
+    db: MemDb,
+    // history before the cursor is replayed for the accounts' notes
+    rescan: bool,
+}
+
+/// An uploaded snapshot handed to the ingestion loop, which swaps it in
+/// between two transactions and replies with the cursor sync resumes from.
+pub struct Import {
+    pub prepared: Prepared,
+    pub reply: oneshot::Sender<Result<Option<Cursor>, String>>,
+}
+
+/// Decodes a snapshot and rebuilds its trees, failing when any does not
+/// produce its recorded root. A snapshot without notes lets history be
+/// replayed from the start when `has_accounts` is set, so their older notes
+/// are found again.
+pub fn prepare(bytes: &[u8], has_accounts: bool) -> Result<Prepared, String> {
+    let snapshot = Snapshot::decode(bytes)?;
+    let rescan = has_accounts && snapshot.state.notes.is_none();
+
+    Ok(Prepared {
+        cursor: snapshot.cursor,
+        db: MemDb::restore(snapshot.state)?,
+        rescan,
+    })
+}
+
+/// Checks every tree of a snapshot against the program's account for it. A
+/// tree at or past the chain's leaf count must have had the chain's root at
+/// that count. One behind it cannot be checked yet and is returned as
+/// unverified: the replay after the import catches it up, and the tree
+/// checker resyncs it from its first leaf if it is wrong once it gets there.
+pub async fn verify_on_chain(
+    prepared: &Prepared,
+    client: &SolanaClient,
+    program_id: &Pubkey,
+) -> Result<Vec<u64>, String> {
+    let mut unverified = vec![];
+
+    for (tree_number, _, _) in prepared.db.roots() {
+        let (chain_root, chain_leaf_count) = client
+            .get_tree_state(program_id, tree_number)
+            .await
+            .map_err(|err| format!("cannot fetch tree {} from chain: {}", tree_number, err))?;
+
+        match matches_chain(&prepared.db, tree_number, &chain_root, chain_leaf_count) {
+            Some(true) => {}
+            Some(false) => {
+                return Err(format!(
+                    "tree {} does not match the chain at {} leaves",
+                    tree_number, chain_leaf_count
+                ));
+            }
+            None => unverified.push(tree_number),
+        }
+    }
+
+    Ok(unverified)
+}
+
+// whether the tree had the chain's root at the chain's leaf count, None while
+// it is behind the chain and cannot be compared yet
+fn matches_chain(
+    db: &MemDb,
+    tree_number: u64,
+    chain_root: &[u8],
+    chain_leaf_count: u64,
+) -> Option<bool> {
+    let tree = db.tree(tree_number)?;
+    if tree.next_leaf_index() < chain_leaf_count {
+        return None;
+    }
+
+    let matches = tree
+        .root_history()
+        .find(|record| record.leaf_count == chain_leaf_count)
+        .is_some_and(|record| record.root == chain_root);
+    Some(matches)
+}
+
+/// Replaces the state with a prepared snapshot and moves the sync cursor to
+/// it. Returns the cursor live sync resumes from.
+pub async fn restore(
+    memdb: &RwLock<MemDb>,
+    sync: &SyncStatus,
+    metrics: &Metrics,
+    prepared: Prepared,
+) -> Option<Cursor> {
+    metrics.set_tree_sizes(&prepared.db.roots());
+    *memdb.write().await = prepared.db;
+    sync.resume_from(prepared.cursor.clone());
+    if prepared.rescan {
+        sync.clear_resume_point();
+    }
+    if let Some(cursor) = &prepared.cursor {
+        metrics.processed_slot(cursor.slot);
+    }
+
+    prepared.cursor
+}
+
+/// Writes a snapshot into `dir` and deletes the oldest ones so at most
+/// `keep` remain.
+pub fn write_rotating(dir: &Path, snapshot: &Snapshot, keep: usize) -> Result<PathBuf, String> {
+    fs::create_dir_all(dir).map_err(|err| format!("cannot create {:?}: {}", dir, err))?;
+
+    // zero padded slots keep the names in chronological order
+    let slot = snapshot.cursor.as_ref().map(|cursor| cursor.slot).unwrap_or_default();
+    let path = dir.join(format!("{}{:020}.{}", FILE_PREFIX, slot, FILE_EXTENSION));
+
+    // written aside first so a crash never leaves a truncated snapshot
+    let partial = path.with_extension("partial");
+    fs::write(&partial, snapshot.encode()?)
+        .map_err(|err| format!("cannot write {:?}: {}", partial, err))?;
+    fs::rename(&partial, &path).map_err(|err| format!("cannot write {:?}: {}", path, err))?;
+
+    let mut snapshots: Vec<PathBuf> = fs::read_dir(dir)
+        .map_err(|err| format!("cannot list {:?}: {}", dir, err))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+            name.starts_with(FILE_PREFIX) && name.ends_with(FILE_EXTENSION)
+        })
+        .collect();
+    snapshots.sort();
+
+    let expired = snapshots.len().saturating_sub(keep);
+    for old in &snapshots[..expired] {
+        if let Err(err) = fs::remove_file(old) {
+            println!("cannot remove old snapshot {:?}: {}", old, err);
+        }
+    }
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(value: u8) -> Vec<u8> {
+        let mut leaf = vec![0; 32];
+        leaf[31] = value;
+        leaf
+    }
+
+    #[test]
+    fn compares_trees_only_once_they_reach_the_chain() {
+        let mut db = MemDb::new();
+        db.insert(0, 0, vec![leaf(1)], 10).unwrap();
+        let root_at_one = db.root(0).unwrap();
+        db.insert(0, 1, vec![leaf(2)], 11).unwrap();
+
+        assert_eq!(matches_chain(&db, 0, &root_at_one, 1), Some(true));
+        assert_eq!(matches_chain(&db, 0, &leaf(9), 1), Some(false));
+        // behind the chain, left unverified for the replay to catch up
+        assert_eq!(matches_chain(&db, 0, &leaf(9), 3), None);
+    }
+}
@@ -1,49 +1,1129 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
-use std::collections::HashMap;
-
-use axum::Json;
-use base64::{Engine as _, engine::general_purpose};
+use borsh::{BorshDeserialize, BorshSerialize};
 use veil_types::{UTXO, MerkleTreeSparse};
 
-use crate::client::RawData;
-use crate::Data;
+use crate::client::{Note, NoteKey, NoteKind, RootRecord, TxRef};
 
-pub struct MemDb {
+/// Result of placing a batch of leaves at its on-chain position.
+#[derive(Debug, PartialEq, Eq)]
+pub enum LeafPlacement {
+    /// The batch, and any buffered batches it unblocked, were appended.
+    Appended,
+    /// Every leaf of the batch is already in the tree.
+    Duplicate,
+    /// The batch starts past the end of the tree. It is buffered until the
+    /// leaves in between arrive.
+    Gap { next_leaf_index: u64 },
+}
+
+/// Leaves and root history of one tree as stored in a snapshot.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct TreeSnapshot {
+    pub tree_number: u64,
+    pub leaves: Vec<Vec<u8>>,
+    pub root_history: Vec<RootRecord>,
+}
+
+/// Everything the indexer derived from the chain, minus account keys and
+/// batches still waiting for a gap to fill.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct MemDbSnapshot {
+    pub trees: Vec<TreeSnapshot>,
+    pub nullifiers: Vec<(Vec<u8>, TxRef)>,
+    // notes of each account, left out unless requested
+    pub notes: Option<Vec<(String, Vec<(NoteKey, Note)>)>>,
+    provisional: Vec<ProvisionalTx>,
+    applied: Vec<(String, AppliedTx)>,
+}
+
+/// A single on-chain commitment tree rebuilt leaf by leaf.
+pub struct CommitmentTree {
+    tree_number: u64,
     tree: MerkleTreeSparse<32>,
-    utxos: HashMap<u64, UTXO>,
+    leaves: Vec<Vec<u8>>,
+    // every root the tree had, oldest first
+    root_history: Vec<RootRecord>,
+    // batches that arrived ahead of the tree with their slot, keyed by start
+    // position
+    pending: BTreeMap<u64, (Vec<Vec<u8>>, u64)>,
 }
 
-impl MemDb {
-    pub fn new() -> Self {
-        let tree = MerkleTreeSparse::new(0);
+impl CommitmentTree {
+    pub fn new(tree_number: u64) -> Self {
+        CommitmentTree {
+            tree_number,
+            tree: MerkleTreeSparse::new(tree_number),
+            leaves: vec![],
+            root_history: vec![],
+            pending: BTreeMap::new(),
+        }
+    }
 
-        MemDb {
-            tree,
-            utxos: HashMap::new(),
+    /// Rebuilds a tree from a snapshot and checks that its leaves still
+    /// produce the recorded root.
+    pub fn restore(snapshot: TreeSnapshot) -> Result<Self, String> {
+        let mut tree = CommitmentTree::new(snapshot.tree_number);
+        if !snapshot.leaves.is_empty() {
+            tree.tree.insert(snapshot.leaves.clone());
+        }
+        tree.leaves = snapshot.leaves;
+        tree.root_history = snapshot.root_history;
+
+        match tree.root_history.last() {
+            Some(record) if record.leaf_count != tree.next_leaf_index() => Err(format!(
+                "tree {} has {} leaves but its last root covers {}",
+                tree.tree_number,
+                tree.next_leaf_index(),
+                record.leaf_count
+            )),
+            Some(record) if record.root != tree.root() => Err(format!(
+                "root mismatch for tree {}, the leaves do not produce the recorded root",
+                tree.tree_number
+            )),
+            None if !tree.leaves.is_empty() => Err(format!(
+                "tree {} has leaves but no recorded root",
+                tree.tree_number
+            )),
+            _ => Ok(tree),
+        }
+    }
+
+    pub fn snapshot(&self) -> TreeSnapshot {
+        TreeSnapshot {
+            tree_number: self.tree_number,
+            leaves: self.leaves.clone(),
+            root_history: self.root_history.clone(),
         }
     }
 
-    pub fn insert(&mut self, leafs: Vec<Vec<u8>>) -> HashMap<Vec<u8>, u64> {
-        self.tree.insert(leafs)
+    pub fn tree_number(&self) -> u64 {
+        self.tree_number
+    }
+
+    pub fn next_leaf_index(&self) -> u64 {
+        self.leaves.len() as u64
     }
 
     pub fn root(&self) -> Vec<u8> {
         self.tree.root()
     }
 
-    pub fn insert_utxo(&mut self, leaf_index: u64, utxo: UTXO) {
-        self.utxos.insert(leaf_index, utxo);
+    pub fn has_gap(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Start of the first batch buffered past a gap.
+    pub fn next_pending(&self) -> Option<u64> {
+        self.pending.keys().next().copied()
+    }
+
+    /// Position of the first of `leafs` when the tree holds all of them in
+    /// a row.
+    pub fn position_of(&self, leafs: &[Vec<u8>]) -> Option<u64> {
+        let first = leafs.first()?;
+        let start = self.leaves.iter().position(|leaf| leaf == first)?;
+
+        (self.leaves.get(start..start + leafs.len()) == Some(leafs)).then_some(start as u64)
+    }
+
+    /// Root the tree would have with `leafs` appended at its end.
+    pub fn root_with(&self, leafs: &[Vec<u8>]) -> Vec<u8> {
+        let mut tree = MerkleTreeSparse::new(self.tree_number);
+        let leaves: Vec<Vec<u8>> = self.leaves.iter().chain(leafs).cloned().collect();
+        if !leaves.is_empty() {
+            tree.insert(leaves);
+        }
+        tree.root()
     }
 
-    pub fn to_json(&self) -> Json<Data> {
-        let data = RawData {
-            tree_data: self.tree.clone(),
-            utxos_data: self.utxos.clone(),
+    /// Roots the tree had, newest first.
+    pub fn root_history(&self) -> impl Iterator<Item = &RootRecord> {
+        self.root_history.iter().rev()
+    }
+
+    pub fn place(
+        &mut self,
+        start_position: u64,
+        leafs: Vec<Vec<u8>>,
+        slot: u64,
+    ) -> Result<LeafPlacement, String> {
+        let next = self.next_leaf_index();
+
+        if start_position > next {
+            self.pending.insert(start_position, (leafs, slot));
+            return Ok(LeafPlacement::Gap { next_leaf_index: next });
+        }
+
+        let applied = self.append_from(start_position, leafs, slot)?;
+
+        // drain buffered batches that are now contiguous with the tree
+        while let Some(entry) = self.pending.first_entry() {
+            if *entry.key() > self.next_leaf_index() {
+                break;
+            }
+            let (position, (batch, batch_slot)) = entry.remove_entry();
+            self.append_from(position, batch, batch_slot)?;
+        }
+
+        if applied {
+            Ok(LeafPlacement::Appended)
+        } else {
+            Ok(LeafPlacement::Duplicate)
+        }
+    }
+
+    /// Leaves from `from`, at most `limit` of them.
+    pub fn leaves(&self, from: u64, limit: usize) -> Vec<Vec<u8>> {
+        self.leaves
+            .iter()
+            .skip(from as usize)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// The leaf at `leaf_index`, if the tree holds it.
+    pub fn leaf(&self, leaf_index: u64) -> Option<&[u8]> {
+        self.leaves.get(leaf_index as usize).map(Vec::as_slice)
+    }
+
+    /// Sibling hashes from the leaf at `leaf_index` up to the root.
+    pub fn merkle_path(&self, leaf_index: u64) -> Option<Vec<Vec<u8>>> {
+        if leaf_index >= self.next_leaf_index() {
+            return None;
+        }
+
+        Some(self.tree.generate_proof(leaf_index))
+    }
+
+    /// Drops every leaf from `len` on, along with any buffered batch, and
+    /// rebuilds the tree from the leaves that remain. The rebuilt root is
+    /// recorded when no kept record covers exactly the remaining leaves.
+    pub fn truncate(&mut self, len: u64) {
+        if len >= self.next_leaf_index() && self.pending.is_empty() {
+            return;
+        }
+
+        self.leaves.truncate(len as usize);
+        self.root_history.retain(|record| record.leaf_count <= len);
+        self.pending.clear();
+        self.tree = MerkleTreeSparse::new(self.tree_number);
+        if self.leaves.is_empty() {
+            return;
+        }
+        self.tree.insert(self.leaves.clone());
+
+        // cut inside a batch, the root at the cut was never recorded. The
+        // kept batch's slot is the closest known.
+        let last = self.root_history.last();
+        if last.map(|record| record.leaf_count) != Some(len) {
+            let slot = last.map_or(0, |record| record.slot);
+            self.root_history.push(RootRecord {
+                root: self.tree.root(),
+                slot,
+                leaf_count: len,
+            });
+        }
+    }
+
+    // appends the part of the batch past the current end of the tree after
+    // checking that the overlapping part matches what is already there
+    fn append_from(
+        &mut self,
+        start_position: u64,
+        leafs: Vec<Vec<u8>>,
+        slot: u64,
+    ) -> Result<bool, String> {
+        let next = self.next_leaf_index();
+        let overlap = (next - start_position) as usize;
+
+        for (offset, leaf) in leafs.iter().take(overlap).enumerate() {
+            let position = start_position as usize + offset;
+            if self.leaves[position] != *leaf {
+                return Err(format!(
+                    "conflicting commitment at leaf {}, tree already holds a different leaf",
+                    position
+                ));
+            }
+        }
+
+        if leafs.len() <= overlap {
+            return Ok(false);
+        }
+
+        let new_leafs = leafs[overlap..].to_vec();
+        self.tree.insert(new_leafs.clone());
+        self.leaves.extend(new_leafs);
+        self.root_history.push(RootRecord {
+            root: self.tree.root(),
+            slot,
+            leaf_count: self.next_leaf_index(),
+        });
+
+        Ok(true)
+    }
+}
+
+// instructions of a transaction whose events were applied, each with the
+// accounts whose keys it was tried with
+#[derive(BorshSerialize, BorshDeserialize, Clone)]
+struct AppliedTx {
+    slot: u64,
+    finalized: bool,
+    // trees the transaction appended to
+    trees: BTreeSet<u64>,
+    instructions: BTreeMap<usize, BTreeSet<String>>,
+}
+
+// changes made by a transaction that is not finalized yet, kept so they can be
+// undone if its fork is dropped
+#[derive(BorshSerialize, BorshDeserialize, Clone)]
+struct ProvisionalTx {
+    signature: String,
+    slot: u64,
+    // tree sizes before the transaction was applied
+    tree_sizes: BTreeMap<u64, u64>,
+    notes: Vec<(String, NoteKey)>,
+    nullifiers: Vec<Vec<u8>>,
+}
+
+pub struct MemDb {
+    trees: BTreeMap<u64, CommitmentTree>,
+    // provisional transactions in the order they were applied
+    provisional: Vec<ProvisionalTx>,
+    // notes of each registered account, ordered by position
+    notes: HashMap<String, BTreeMap<NoteKey, Note>>,
+    // every nullifier seen on chain and the transaction that revealed it
+    nullifiers: HashMap<Vec<u8>, TxRef>,
+    // nullifier of each owned note
+    note_by_nullifier: HashMap<Vec<u8>, (String, NoteKey)>,
+    // instructions already applied, so events delivered twice are skipped
+    applied: HashMap<String, AppliedTx>,
+}
+
+impl MemDb {
+    pub fn new() -> Self {
+        MemDb {
+            trees: BTreeMap::new(),
+            provisional: vec![],
+            notes: HashMap::new(),
+            nullifiers: HashMap::new(),
+            note_by_nullifier: HashMap::new(),
+            applied: HashMap::new(),
+        }
+    }
+
+    /// Copies the state into a snapshot, with every account's notes when
+    /// `include_notes` is set.
+    pub fn snapshot(&self, include_notes: bool) -> MemDbSnapshot {
+        let notes = include_notes.then(|| {
+            self.notes
+                .iter()
+                .map(|(account, notes)| {
+                    let notes = notes.iter().map(|(key, note)| (*key, note.clone())).collect();
+                    (account.clone(), notes)
+                })
+                .collect()
+        });
+
+        MemDbSnapshot {
+            trees: self.trees.values().map(|tree| tree.snapshot()).collect(),
+            nullifiers: self
+                .nullifiers
+                .iter()
+                .map(|(nullifier, spent_by)| (nullifier.clone(), spent_by.clone()))
+                .collect(),
+            notes,
+            provisional: self.provisional.clone(),
+            applied: self
+                .applied
+                .iter()
+                .map(|(signature, applied)| (signature.clone(), applied.clone()))
+                .collect(),
+        }
+    }
+
+    /// Rebuilds the state from a snapshot, failing when any tree does not
+    /// produce its recorded root.
+    pub fn restore(snapshot: MemDbSnapshot) -> Result<Self, String> {
+        let mut db = MemDb::new();
+
+        for tree in snapshot.trees {
+            let tree = CommitmentTree::restore(tree)?;
+            db.trees.insert(tree.tree_number, tree);
+        }
+        db.nullifiers = snapshot.nullifiers.into_iter().collect();
+        db.provisional = snapshot.provisional;
+        // without notes, applying an instruction again is the only way to
+        // find them
+        if snapshot.notes.is_some() {
+            db.applied = snapshot.applied.into_iter().collect();
+        }
+
+        for (account, notes) in snapshot.notes.into_iter().flatten() {
+            for (key, note) in notes {
+                db.note_by_nullifier
+                    .insert(note.nullifier.clone(), (account.clone(), key));
+                db.notes.entry(account.clone()).or_default().insert(key, note);
+            }
+        }
+
+        Ok(db)
+    }
+
+    /// Places `leafs` landed at `slot` at `start_position` of tree
+    /// `tree_number`, creating the tree on its first leaf.
+    pub fn insert(
+        &mut self,
+        tree_number: u64,
+        start_position: u64,
+        leafs: Vec<Vec<u8>>,
+        slot: u64,
+    ) -> Result<LeafPlacement, String> {
+        self.trees
+            .entry(tree_number)
+            .or_insert_with(|| CommitmentTree::new(tree_number))
+            .place(start_position, leafs, slot)
+    }
+
+    pub fn root(&self, tree_number: u64) -> Option<Vec<u8>> {
+        self.trees.get(&tree_number).map(|tree| tree.root())
+    }
+
+    /// Root and leaf count of every tree.
+    pub fn roots(&self) -> Vec<(u64, Vec<u8>, u64)> {
+        self.trees
+            .iter()
+            .map(|(tree_number, tree)| (*tree_number, tree.root(), tree.next_leaf_index()))
+            .collect()
+    }
+
+    pub fn tree(&self, tree_number: u64) -> Option<&CommitmentTree> {
+        self.trees.get(&tree_number)
+    }
+
+    pub fn notes(&self, account: &str) -> Option<&BTreeMap<NoteKey, Note>> {
+        self.notes.get(account)
+    }
+
+    pub fn note(&self, account: &str, key: &NoteKey) -> Option<&Note> {
+        self.notes.get(account).and_then(|notes| notes.get(key))
+    }
+
+    /// Transaction that revealed `nullifier`, if it was seen on chain.
+    pub fn nullifier(&self, nullifier: &[u8]) -> Option<&TxRef> {
+        self.nullifiers.get(nullifier)
+    }
+
+    pub fn latest_tree_number(&self) -> Option<u64> {
+        self.trees.keys().next_back().copied()
+    }
+
+    /// Leaves of a tree no provisional transaction can roll back.
+    pub fn finalized_leaf_count(&self, tree_number: u64) -> u64 {
+        let leaf_count = self
+            .tree(tree_number)
+            .map_or(0, |tree| tree.next_leaf_index());
+
+        // a tree missing from a journal entry did not exist before it
+        self.provisional
+            .iter()
+            .map(|tx| tx.tree_sizes.get(&tree_number).copied().unwrap_or(0))
+            .fold(leaf_count, u64::min)
+    }
+
+    /// Trees still waiting for leaves in front of a buffered batch.
+    pub fn trees_with_gaps(&self) -> Vec<u64> {
+        self.trees
+            .iter()
+            .filter(|(_, tree)| tree.has_gap())
+            .map(|(tree_number, _)| *tree_number)
+            .collect()
+    }
+
+    /// Where `leafs` whose event was lost landed, when the trees and the
+    /// chain prove it. `logged` are the batches of the same transaction that
+    /// logged their position ahead of them and `chain` the newest tree's
+    /// number, root and leaf count on chain.
+    ///
+    /// Leaves a tree already holds in a row are where they are. Otherwise
+    /// they follow everything known of a tree and must end exactly where its
+    /// next buffered batch starts, or where the chain's tree ends with the
+    /// same root. Anything else is a guess and yields `None`.
+    pub fn prove_position(
+        &self,
+        logged: &[(u64, u64, Vec<Vec<u8>>)],
+        leafs: &[Vec<u8>],
+        chain: Option<&(u64, Vec<u8>, u64)>,
+    ) -> Option<(u64, u64)> {
+        let held = self.trees.values().find_map(|tree| {
+            tree.position_of(leafs).map(|start| (tree.tree_number(), start))
+        });
+        if held.is_some() {
+            return held;
+        }
+
+        let mut candidates: BTreeSet<u64> = self.trees_with_gaps().into_iter().collect();
+        candidates.extend(logged.iter().map(|(tree_number, _, _)| *tree_number));
+        candidates.extend(chain.map(|(tree_number, _, _)| *tree_number));
+
+        for tree_number in candidates {
+            let empty = CommitmentTree::new(tree_number);
+            let tree = self.trees.get(&tree_number).unwrap_or(&empty);
+
+            // the transaction's own batches go first, they must continue the
+            // tree for its end to be known
+            let mut batches: Vec<&(u64, u64, Vec<Vec<u8>>)> = logged
+                .iter()
+                .filter(|(batch_tree, _, _)| *batch_tree == tree_number)
+                .collect();
+            batches.sort_by_key(|(_, start, _)| *start);
+            let mut ahead: Vec<Vec<u8>> = vec![];
+            let mut end = tree.next_leaf_index();
+            let mut contiguous = true;
+            for (_, start, batch) in batches {
+                let batch_end = start + batch.len() as u64;
+                if *start > end {
+                    contiguous = false;
+                    break;
+                }
+                if batch_end > end {
+                    ahead.extend(batch[(end - start) as usize..].iter().cloned());
+                    end = batch_end;
+                }
+            }
+            if !contiguous {
+                continue;
+            }
+
+            let leaf_count = end + leafs.len() as u64;
+            if tree.next_pending() == Some(leaf_count) {
+                return Some((tree_number, end));
+            }
+            if let Some((chain_tree, chain_root, chain_leaf_count)) = chain {
+                if *chain_tree == tree_number && *chain_leaf_count == leaf_count {
+                    ahead.extend(leafs.iter().cloned());
+                    if tree.root_with(&ahead) == *chain_root {
+                        return Some((tree_number, end));
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Starts journaling a transaction that is only confirmed. Every change
+    /// made until the next call is undone if the transaction is rolled back.
+    pub fn begin_provisional(&mut self, signature: &str, slot: u64) {
+        if self.provisional.iter().any(|tx| tx.signature == signature) {
+            return;
+        }
+
+        self.provisional.push(ProvisionalTx {
+            signature: signature.to_string(),
+            slot,
+            tree_sizes: self
+                .trees
+                .iter()
+                .map(|(tree_number, tree)| (*tree_number, tree.next_leaf_index()))
+                .collect(),
+            notes: vec![],
+            nullifiers: vec![],
+        });
+    }
+
+    /// Signatures and slots of the transactions still waiting for finality.
+    pub fn provisional_transactions(&self) -> Vec<(String, u64)> {
+        self.provisional
+            .iter()
+            .map(|tx| (tx.signature.clone(), tx.slot))
+            .collect()
+    }
+
+    /// Marks everything a provisional transaction changed as finalized and
+    /// returns the notes it created or spent.
+    pub fn promote(&mut self, signature: &str) -> Vec<(String, NoteKey)> {
+        let position = match self.provisional.iter().position(|tx| tx.signature == signature) {
+            Some(position) => position,
+            None => return vec![],
         };
+        let tx = self.provisional.remove(position);
+        if let Some(applied) = self.applied.get_mut(signature) {
+            applied.finalized = true;
+        }
 
-        let data_bytes = borsh::to_vec(&data).unwrap();
-        let encoded = general_purpose::STANDARD.encode(&data_bytes);
+        let mut promoted = vec![];
+        for (account, key) in tx.notes {
+            if let Some(note) = self.notes.get_mut(&account).and_then(|notes| notes.get_mut(&key)) {
+                note.created_by.finalized = true;
+                promoted.push((account, key));
+            }
+        }
+
+        for nullifier in tx.nullifiers {
+            if let Some(spent_by) = self.nullifiers.get_mut(&nullifier) {
+                spent_by.finalized = true;
+            }
+
+            if let Some((account, key)) = self.note_by_nullifier.get(&nullifier) {
+                let note = self.notes.get_mut(account).and_then(|notes| notes.get_mut(key));
+                if let Some(spent_by) = note.and_then(|note| note.spent.as_mut()) {
+                    spent_by.finalized = true;
+                    promoted.push((account.clone(), *key));
+                }
+            }
+        }
+
+        promoted
+    }
+
+    /// Undoes a dropped transaction together with everything applied after
+    /// it, restoring the trees and notes to the state before it. Returns the
+    /// signatures that were rolled back.
+    pub fn rollback(&mut self, signature: &str) -> Vec<String> {
+        let position = match self.provisional.iter().position(|tx| tx.signature == signature) {
+            Some(position) => position,
+            None => return vec![],
+        };
+        let undone: Vec<ProvisionalTx> = self.provisional.drain(position..).collect();
+
+        for tx in undone.iter().rev() {
+            for nullifier in &tx.nullifiers {
+                self.nullifiers.remove(nullifier);
+
+                if let Some((account, key)) = self.note_by_nullifier.get(nullifier) {
+                    let note = self.notes.get_mut(account).and_then(|notes| notes.get_mut(key));
+                    if let Some(note) = note {
+                        note.spent = None;
+                    }
+                }
+            }
+
+            for (account, key) in &tx.notes {
+                let note = self.notes.get_mut(account).and_then(|notes| notes.remove(key));
+                if let Some(note) = note {
+                    self.note_by_nullifier.remove(&note.nullifier);
+                }
+            }
+        }
+
+        for tx in &undone {
+            self.applied.remove(&tx.signature);
+        }
+
+        // leaves are append only, so the sizes before the first undone
+        // transaction cover every later one. Finalized transactions applied
+        // since then were never journaled but lose their leaves too, the
+        // reset forgets them so the refetch puts them back
+        let tree_sizes = &undone[0].tree_sizes;
+        let shrunk: Vec<(u64, u64)> = self
+            .trees
+            .iter()
+            .filter_map(|(tree_number, tree)| {
+                let size = tree_sizes.get(tree_number).copied().unwrap_or(0);
+                (size < tree.next_leaf_index() || tree.has_gap()).then_some((*tree_number, size))
+            })
+            .collect();
+        for (tree_number, size) in shrunk {
+            self.reset_tree(tree_number, size);
+        }
+
+        undone.into_iter().map(|tx| tx.signature).collect()
+    }
+
+    /// Stores a note owned by `account`. The note is marked spent straight
+    /// away when its nullifier was already observed. Returns false when the
+    /// note was already known.
+    pub fn insert_note(
+        &mut self,
+        account: &str,
+        key: NoteKey,
+        utxo: UTXO,
+        nullifier: Vec<u8>,
+        kind: NoteKind,
+        created_by: TxRef,
+    ) -> bool {
+        let spent = self.nullifiers.get(&nullifier).cloned();
+
+        let existing = self.notes.get(account).and_then(|notes| notes.get(&key));
+        let is_new = existing.is_none();
+        if existing.is_some_and(|note| note.created_by.finalized || !created_by.finalized) {
+            return false;
+        }
+
+        if !created_by.finalized {
+            if let Some(tx) = self.journal(&created_by.signature) {
+                tx.notes.push((account.to_string(), key));
+            }
+        }
+
+        self.note_by_nullifier
+            .insert(nullifier.clone(), (account.to_string(), key));
+        self.notes.entry(account.to_string()).or_default().insert(
+            key,
+            Note {
+                utxo,
+                nullifier,
+                kind,
+                created_by,
+                spent,
+            },
+        );
+
+        is_new
+    }
+
+    /// Records nullifiers revealed by a transaction and returns the owned
+    /// notes they spend.
+    pub fn insert_nullifiers(
+        &mut self,
+        nullifiers: Vec<Vec<u8>>,
+        spent_by: TxRef,
+    ) -> Vec<(String, NoteKey)> {
+        let mut spent_notes = vec![];
+
+        for nullifier in nullifiers {
+            if let Some(known) = self.nullifiers.get(&nullifier) {
+                // replays of the same spend only ever upgrade it to finalized
+                if known.finalized || !spent_by.finalized {
+                    continue;
+                }
+            } else if !spent_by.finalized {
+                if let Some(tx) = self.journal(&spent_by.signature) {
+                    tx.nullifiers.push(nullifier.clone());
+                }
+            }
+
+            if let Some((account, key)) = self.note_by_nullifier.get(&nullifier) {
+                let note = self
+                    .notes
+                    .get_mut(account)
+                    .and_then(|notes| notes.get_mut(key));
+                if let Some(note) = note {
+                    note.spent = Some(spent_by.clone());
+                    spent_notes.push((account.clone(), *key));
+                }
+            }
+
+            self.nullifiers.insert(nullifier, spent_by.clone());
+        }
+
+        spent_notes
+    }
+
+    /// Drops the leaves of a tree from `len` on along with the notes they
+    /// hold, so they can be replayed. Returns the dropped notes.
+    pub fn reset_tree(&mut self, tree_number: u64, len: u64) -> Vec<(String, NoteKey)> {
+        if let Some(tree) = self.trees.get_mut(&tree_number) {
+            tree.truncate(len);
+        }
+
+        // forget what was applied to the tree from the slot of the last root
+        // kept on, whatever is replayed from there and still in place is a
+        // duplicate
+        let kept_slot = self
+            .trees
+            .get(&tree_number)
+            .and_then(|tree| tree.root_history().next())
+            .map_or(0, |record| record.slot);
+        self.applied
+            .retain(|_, applied| applied.slot < kept_slot || !applied.trees.contains(&tree_number));
+        self.trees.retain(|_, tree| tree.next_leaf_index() > 0);
+
+        let from = NoteKey { tree_number, leaf_index: len };
+        let to = NoteKey { tree_number: tree_number + 1, leaf_index: 0 };
+        let mut dropped = vec![];
+        for (account, notes) in self.notes.iter_mut() {
+            let keys: Vec<NoteKey> = notes.range(from..to).map(|(key, _)| *key).collect();
+            for key in keys {
+                if let Some(note) = notes.remove(&key) {
+                    self.note_by_nullifier.remove(&note.nullifier);
+                    dropped.push((account.clone(), key));
+                }
+            }
+        }
+
+        dropped
+    }
+
+    /// Whether the tree holds `commitment` at the position of `key`.
+    pub fn holds(&self, key: &NoteKey, commitment: &[u8]) -> bool {
+        self.tree(key.tree_number)
+            .and_then(|tree| tree.leaf(key.leaf_index))
+            .is_some_and(|leaf| leaf == commitment)
+    }
+
+    /// Accounts whose keys an instruction was applied with, None when its
+    /// events were not applied yet.
+    pub fn applied_accounts(
+        &self,
+        signature: &str,
+        instruction_index: usize,
+    ) -> Option<&BTreeSet<String>> {
+        self.applied
+            .get(signature)
+            .and_then(|applied| applied.instructions.get(&instruction_index))
+    }
+
+    /// Records that the events of `instructions`, appending to `trees`, were
+    /// applied with the keys of `accounts`.
+    pub fn mark_applied(
+        &mut self,
+        signature: &str,
+        slot: u64,
+        finalized: bool,
+        trees: impl IntoIterator<Item = u64>,
+        instructions: impl IntoIterator<Item = usize>,
+        accounts: &[String],
+    ) {
+        let applied = self
+            .applied
+            .entry(signature.to_string())
+            .or_insert_with(|| AppliedTx {
+                slot,
+                finalized,
+                trees: BTreeSet::new(),
+                instructions: BTreeMap::new(),
+            });
+        applied.finalized |= finalized;
+        applied.trees.extend(trees);
+        for instruction in instructions {
+            applied
+                .instructions
+                .entry(instruction)
+                .or_default()
+                .extend(accounts.iter().cloned());
+        }
+    }
+
+    /// Forgets the applied instructions of finalized transactions that
+    /// landed before `slot`.
+    pub fn prune_applied(&mut self, slot: u64) {
+        self.applied
+            .retain(|_, applied| !applied.finalized || applied.slot >= slot);
+    }
+
+    // journal entry of a provisional transaction
+    fn journal(&mut self, signature: &str) -> Option<&mut ProvisionalTx> {
+        self.provisional.iter_mut().find(|tx| tx.signature == signature)
+    }
+
+    /// Whether any of `nullifiers` spends a note of `account`.
+    pub fn spends_from(&self, account: &str, nullifiers: &[Vec<u8>]) -> bool {
+        nullifiers.iter().any(|nullifier| {
+            self.note_by_nullifier
+                .get(nullifier)
+                .is_some_and(|(owner, _)| owner == account)
+        })
+    }
+
+    /// Drops every note of `account`, and forgets it was tried on applied
+    /// instructions so the name can be registered again.
+    pub fn remove_account(&mut self, account: &str) {
+        if let Some(notes) = self.notes.remove(account) {
+            notes.values().for_each(|note| {
+                self.note_by_nullifier.remove(&note.nullifier);
+            });
+        }
+        for applied in self.applied.values_mut() {
+            applied.instructions.values_mut().for_each(|accounts| {
+                accounts.remove(account);
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(value: u8) -> Vec<u8> {
+        let mut leaf = vec![0; 32];
+        leaf[31] = value;
+        leaf
+    }
+
+    fn tx_ref(signature: &str, slot: u64, finalized: bool) -> TxRef {
+        TxRef {
+            signature: signature.to_string(),
+            slot,
+            finalized,
+            block_time: None,
+        }
+    }
+
+    fn utxo() -> UTXO {
+        let random = || (0..32).map(|_| rand::random()).collect::<Vec<u8>>();
+        UTXO::new(random(), random(), vec![1; 32], random(), random(), 5, String::new())
+    }
+
+    #[test]
+    fn places_leaves_at_their_position() {
+        let mut tree = CommitmentTree::new(0);
+
+        assert_eq!(tree.place(0, vec![leaf(1), leaf(2)], 10), Ok(LeafPlacement::Appended));
+        // a replay of the batch, or of part of it, changes nothing
+        assert_eq!(tree.place(0, vec![leaf(1), leaf(2)], 10), Ok(LeafPlacement::Duplicate));
+        assert_eq!(tree.place(1, vec![leaf(2)], 10), Ok(LeafPlacement::Duplicate));
+        // an overlapping batch appends what is new
+        assert_eq!(tree.place(1, vec![leaf(2), leaf(3)], 11), Ok(LeafPlacement::Appended));
+
+        assert_eq!(tree.next_leaf_index(), 3);
+        assert_eq!(tree.leaf(2), Some(leaf(3).as_slice()));
+        assert_eq!(tree.root_history().count(), 2);
+    }
+
+    #[test]
+    fn buffers_batches_past_a_gap() {
+        let mut tree = CommitmentTree::new(0);
+        tree.place(0, vec![leaf(1)], 10).unwrap();
+
+        assert_eq!(
+            tree.place(2, vec![leaf(3)], 12),
+            Ok(LeafPlacement::Gap { next_leaf_index: 1 })
+        );
+        assert!(tree.has_gap());
+        assert_eq!(tree.next_leaf_index(), 1);
+
+        // the missing leaf unblocks the buffered batch
+        assert_eq!(tree.place(1, vec![leaf(2)], 11), Ok(LeafPlacement::Appended));
+        assert!(!tree.has_gap());
+        assert_eq!(tree.next_leaf_index(), 3);
+
+        let mut in_order = CommitmentTree::new(0);
+        in_order.place(0, vec![leaf(1), leaf(2), leaf(3)], 10).unwrap();
+        assert_eq!(tree.root(), in_order.root());
+    }
+
+    #[test]
+    fn proves_positions_from_the_trees_and_the_chain() {
+        let mut db = MemDb::new();
+        db.insert(0, 0, vec![leaf(1), leaf(2)], 10).unwrap();
+
+        // held already, as on a replay
+        assert_eq!(db.prove_position(&[], &[leaf(2)], None), Some((0, 1)));
+        // nothing says where the lost leaves go
+        assert_eq!(db.prove_position(&[], &[leaf(3)], None), None);
+
+        // the chain's tree ends right after them, with their root
+        let mut expected = MemDb::new();
+        expected.insert(0, 0, vec![leaf(1), leaf(2), leaf(3), leaf(4)], 10).unwrap();
+        let chain = (0, expected.root(0).unwrap(), 4);
+        let logged = vec![(0, 2, vec![leaf(3)])];
+        assert_eq!(db.prove_position(&logged, &[leaf(4)], Some(&chain)), Some((0, 3)));
+        assert_eq!(db.prove_position(&[], &[leaf(4)], Some(&chain)), None);
+
+        // or on a fresh tree after a rollover
+        let mut rolled = MemDb::new();
+        rolled.insert(1, 0, vec![leaf(5)], 10).unwrap();
+        let chain = (1, rolled.root(1).unwrap(), 1);
+        assert_eq!(db.prove_position(&[], &[leaf(5)], Some(&chain)), Some((1, 0)));
+
+        // the next known batch starts right after them
+        db.insert(0, 4, vec![leaf(5)], 12).unwrap();
+        assert_eq!(db.prove_position(&[], &[leaf(3), leaf(4)], None), Some((0, 2)));
+        assert_eq!(db.prove_position(&[], &[leaf(3)], None), None);
+    }
+
+    #[test]
+    fn records_the_root_left_by_a_truncation() {
+        let mut tree = CommitmentTree::new(0);
+        tree.place(0, vec![leaf(1)], 10).unwrap();
+        tree.place(1, vec![leaf(2), leaf(3)], 11).unwrap();
+
+        tree.truncate(2);
+
+        let mut expected = CommitmentTree::new(0);
+        expected.place(0, vec![leaf(1), leaf(2)], 10).unwrap();
+        let newest = tree.root_history().next().unwrap();
+        assert_eq!((newest.leaf_count, newest.slot), (2, 10));
+        assert_eq!(newest.root, expected.root());
+        assert_eq!(tree.root_history().count(), 2);
+        // the history agrees with the leaves again
+        assert!(CommitmentTree::restore(tree.snapshot()).is_ok());
+
+        // cut at a recorded root, nothing to add
+        tree.truncate(1);
+        assert_eq!(tree.root_history().count(), 1);
+    }
+
+    #[test]
+    fn rejects_conflicting_leaves() {
+        let mut tree = CommitmentTree::new(0);
+        tree.place(0, vec![leaf(1), leaf(2)], 10).unwrap();
+
+        assert!(tree.place(1, vec![leaf(9), leaf(3)], 11).is_err());
+        assert_eq!(tree.next_leaf_index(), 2);
+        assert_eq!(tree.leaf(1), Some(leaf(2).as_slice()));
+    }
+
+    #[test]
+    fn marks_notes_spent_in_either_order() {
+        let mut db = MemDb::new();
+        let key = |leaf_index| NoteKey { tree_number: 0, leaf_index };
+
+        // the note is stored first, its spend lands later
+        let created = tx_ref("a", 10, true);
+        db.insert_note("alice", key(0), utxo(), leaf(7), NoteKind::Deposit, created);
+        assert!(db.note("alice", &key(0)).is_some_and(|note| note.spent.is_none()));
+        let spent = db.insert_nullifiers(vec![leaf(7)], tx_ref("b", 11, true));
+        assert_eq!(spent, vec![("alice".to_string(), key(0))]);
+        assert_eq!(
+            db.note("alice", &key(0)).and_then(|note| note.spent.as_ref()),
+            Some(&tx_ref("b", 11, true))
+        );
+
+        // a replay finds the spend before the note it spends
+        db.insert_nullifiers(vec![leaf(8)], tx_ref("c", 12, true));
+        let created = tx_ref("d", 9, true);
+        db.insert_note("alice", key(1), utxo(), leaf(8), NoteKind::Change, created);
+        assert_eq!(
+            db.note("alice", &key(1)).and_then(|note| note.spent.as_ref()),
+            Some(&tx_ref("c", 12, true))
+        );
+        assert!(db.spends_from("alice", &[leaf(8)]));
+        assert!(!db.spends_from("bob", &[leaf(8)]));
+    }
 
-        Json(Data { data: encoded })
+    #[test]
+    fn keeps_how_and_when_a_note_arrived() {
+        let mut db = MemDb::new();
+        let key = NoteKey { tree_number: 0, leaf_index: 0 };
+        let created = TxRef {
+            block_time: Some(1_700_000_000),
+            ..tx_ref("a", 10, false)
+        };
+
+        db.begin_provisional("a", 10);
+        db.insert_note("alice", key, utxo(), leaf(7), NoteKind::Incoming, created);
+        db.promote("a");
+
+        let restored = MemDb::restore(db.snapshot(true)).unwrap();
+        let note = restored.note("alice", &key).unwrap();
+        assert_eq!(note.kind, NoteKind::Incoming);
+        assert_eq!(note.created_by.signature, "a");
+        assert_eq!(note.created_by.block_time, Some(1_700_000_000));
+        assert!(note.created_by.finalized);
+    }
+
+    #[test]
+    fn rollback_undoes_later_transactions_too() {
+        let mut db = MemDb::new();
+        db.insert(0, 0, vec![leaf(1), leaf(2)], 10).unwrap();
+        let finalized_root = db.root(0);
+
+        db.begin_provisional("dropped", 11);
+        db.insert(0, 2, vec![leaf(3)], 11).unwrap();
+        db.insert_nullifiers(vec![leaf(7)], tx_ref("dropped", 11, false));
+        db.mark_applied("dropped", 11, false, [0], [0], &[]);
+
+        db.begin_provisional("later", 12);
+        db.insert(0, 3, vec![leaf(4)], 12).unwrap();
+        db.mark_applied("later", 12, false, [0], [0], &[]);
+
+        assert_eq!(db.finalized_leaf_count(0), 2);
+        assert_eq!(db.rollback("dropped"), vec!["dropped", "later"]);
+
+        assert_eq!(db.tree(0).unwrap().next_leaf_index(), 2);
+        assert_eq!(db.root(0), finalized_root);
+        assert!(db.nullifier(&leaf(7)).is_none());
+        assert!(db.applied_accounts("dropped", 0).is_none());
+        assert!(db.applied_accounts("later", 0).is_none());
+        assert!(db.provisional_transactions().is_empty());
+    }
+
+    #[test]
+    fn rollback_forgets_finalized_leaves_it_drops() {
+        let mut db = MemDb::new();
+        db.insert(0, 0, vec![leaf(1)], 5).unwrap();
+        db.mark_applied("first", 5, true, [0], [0], &[]);
+        db.insert(0, 1, vec![leaf(2)], 10).unwrap();
+        db.mark_applied("second", 10, true, [0], [0], &[]);
+
+        db.begin_provisional("dropped", 11);
+        db.insert(0, 2, vec![leaf(3)], 11).unwrap();
+        // landed after it in a history replay, never journaled
+        db.insert(0, 3, vec![leaf(4)], 12).unwrap();
+        db.mark_applied("replayed", 12, true, [0], [0], &[]);
+
+        db.rollback("dropped");
+
+        // the refetch applies it again and puts its leaf back
+        assert_eq!(db.tree(0).unwrap().next_leaf_index(), 2);
+        assert!(db.applied_accounts("replayed", 0).is_none());
+        // from the slot of the last root kept on, a replay is a duplicate
+        assert!(db.applied_accounts("second", 0).is_none());
+        assert!(db.applied_accounts("first", 0).is_some());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn promote_keeps_the_changes() {
+        let mut db = MemDb::new();
+        db.begin_provisional("confirmed", 11);
+        db.insert(0, 0, vec![leaf(1)], 11).unwrap();
+        db.insert_nullifiers(vec![leaf(7)], tx_ref("confirmed", 11, false));
+        db.mark_applied("confirmed", 11, false, [0], [0], &[]);
+        assert_eq!(db.finalized_leaf_count(0), 0);
+
+        db.promote("confirmed");
+
+        assert_eq!(db.finalized_leaf_count(0), 1);
+        assert_eq!(db.nullifier(&leaf(7)).map(|spent_by| spent_by.finalized), Some(true));
+        assert!(db.rollback("confirmed").is_empty());
+        assert_eq!(db.tree(0).unwrap().next_leaf_index(), 1);
+    }
+
+    #[test]
+    fn reset_forgets_only_what_touched_the_tree() {
+        let mut db = MemDb::new();
+        db.insert(0, 0, vec![leaf(1)], 3).unwrap();
+        db.mark_applied("old", 3, true, [0], [0], &[]);
+        db.insert(0, 1, vec![leaf(2)], 12).unwrap();
+        db.mark_applied("dropped", 12, true, [0], [0], &[]);
+        db.insert(1, 0, vec![leaf(3)], 12).unwrap();
+        db.mark_applied("other tree", 12, true, [1], [0], &[]);
+        db.mark_applied("spend", 12, true, [], [0], &[]);
+
+        db.reset_tree(0, 1);
+
+        assert_eq!(db.tree(0).unwrap().next_leaf_index(), 1);
+        assert!(db.applied_accounts("dropped", 0).is_none());
+        // the tree kept its leaf, replaying it changes nothing
+        assert!(db.applied_accounts("old", 0).is_none());
+        assert!(db.applied_accounts("other tree", 0).is_some());
+        assert!(db.applied_accounts("spend", 0).is_some());
+        assert_eq!(db.tree(1).unwrap().next_leaf_index(), 1);
+    }
+
+    #[test]
+    fn remembers_accounts_per_instruction() {
+        let mut db = MemDb::new();
+        db.mark_applied("tx", 10, true, [0], [0, 1], &["alice".to_string()]);
+        db.mark_applied("tx", 10, true, [0], [1], &["bob".to_string()]);
+
+        let tried = |index| db.applied_accounts("tx", index).cloned().unwrap_or_default();
+        assert_eq!(tried(0), BTreeSet::from(["alice".to_string()]));
+        assert_eq!(tried(1), BTreeSet::from(["alice".to_string(), "bob".to_string()]));
+        assert!(db.applied_accounts("tx", 2).is_none());
+
+        db.remove_account("alice");
+        assert!(db.applied_accounts("tx", 0).is_some_and(|tried| tried.is_empty()));
+    }
+
+    #[test]
+    fn prunes_finalized_entries_behind_the_cursor() {
+        let mut db = MemDb::new();
+        db.mark_applied("old", 10, true, [0], [0], &[]);
+        db.mark_applied("confirmed", 10, false, [0], [0], &[]);
+        db.mark_applied("new", 12, true, [0], [0], &[]);
+
+        // finalized and behind the cursor, applying it again changes nothing
+        db.prune_applied(12);
+
+        assert!(db.applied_accounts("old", 0).is_none());
+        assert!(db.applied_accounts("confirmed", 0).is_some());
+        assert!(db.applied_accounts("new", 0).is_some());
+    }
+}
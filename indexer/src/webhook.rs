@@ -0,0 +1,479 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose, Engine as _};
+use hmac::{Hmac, Mac};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::sync::{Mutex, RwLock};
+
+use crate::{
+    account::generate_token,
+    api_handler::v2::NoteView,
+    client::{Note, NoteKey, TxRef},
+};
+
+// the transaction that created or spent the note reached finality
+pub const NOTE_FINALIZED: &str = "note_finalized";
+
+pub const SIGNATURE_HEADER: &str = "X-Veil-Signature";
+pub const TIMESTAMP_HEADER: &str = "X-Veil-Timestamp";
+pub const EVENT_HEADER: &str = "X-Veil-Event";
+pub const DELIVERY_HEADER: &str = "X-Veil-Delivery";
+
+// attempts per delivery before it is marked failed and left for a replay
+const MAX_ATTEMPTS: u32 = 6;
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// deliveries kept in the log, the oldest are dropped first
+const LOG_SIZE: usize = 10_000;
+
+/// Body POSTed to a webhook.
+#[derive(Serialize, Clone, Debug)]
+pub struct WebhookPayload {
+    pub event: &'static str,
+    pub account: String,
+    pub amount: u64,
+    pub token: String, // token mint address
+    pub memo: String,
+    pub tree_number: u64,
+    pub leaf_index: u64,
+    // transaction the event is about
+    pub signature: String,
+    pub slot: u64,
+    pub finalized: bool,
+    pub block_time: Option<i64>, // unix seconds
+}
+
+impl WebhookPayload {
+    pub fn new(event: &'static str, account: &str, key: &NoteKey, note: &Note, tx: &TxRef) -> Self {
+        let view = NoteView::new(key, note);
+
+        WebhookPayload {
+            event,
+            account: account.to_string(),
+            amount: view.amount,
+            token: view.token,
+            memo: view.memo,
+            tree_number: key.tree_number,
+            leaf_index: key.leaf_index,
+            signature: tx.signature.clone(),
+            slot: tx.slot,
+            finalized: tx.finalized,
+            block_time: tx.block_time,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+/// One payload on its way to a webhook, as shown in the delivery log.
+#[derive(Serialize, Clone, Debug)]
+pub struct Delivery {
+    pub id: u64,
+    pub url: String,
+    pub payload: WebhookPayload,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub created_at: u64, // unix seconds
+    pub updated_at: u64,
+}
+
+struct Webhook {
+    url: String,
+    secret: String,
+}
+
+struct DeliveryLog {
+    next_id: u64,
+    deliveries: VecDeque<Delivery>,
+}
+
+impl DeliveryLog {
+    fn get_mut(&mut self, id: u64) -> Option<&mut Delivery> {
+        self.deliveries.iter_mut().find(|delivery| delivery.id == id)
+    }
+}
+
+/// Webhooks of registered accounts and the log of what was sent to them.
+///
+/// Every payload carries `X-Veil-Signature`, the base64 HMAC-SHA256 of
+/// `<timestamp>.<body>` keyed with the webhook's secret, and the timestamp
+/// in `X-Veil-Timestamp`, so receivers can check it came from us and is not
+/// a stale replay.
+///
+/// Webhooks, the delivery log and pending retries live in memory only. A
+/// delivery is retried while the indexer runs, so a receiver may see it
+/// twice and should dedupe on `X-Veil-Delivery`, but a restart drops every
+/// pending one without a trace. Receivers that cannot miss a payload
+/// reconcile against the account's notes after a restart.
+pub struct Webhooks {
+    hooks: RwLock<HashMap<String, Webhook>>,
+    log: Mutex<DeliveryLog>,
+    http: reqwest::Client,
+}
+
+impl Webhooks {
+    pub fn new() -> Self {
+        Webhooks {
+            hooks: RwLock::new(HashMap::new()),
+            log: Mutex::new(DeliveryLog {
+                next_id: 1,
+                deliveries: VecDeque::new(),
+            }),
+            http: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Points the account's webhook at `url`, replacing any previous one.
+    /// Returns the secret payloads are signed with.
+    pub async fn set(&self, account: &str, url: &str) -> Result<String, String> {
+        let parsed = Url::parse(url).map_err(|err| format!("invalid webhook url: {}", err))?;
+        if parsed.scheme() != "http" && parsed.scheme() != "https" {
+            return Err("webhook url must be http or https".to_string());
+        }
+
+        let secret = generate_token();
+        self.hooks.write().await.insert(
+            account.to_string(),
+            Webhook {
+                url: url.to_string(),
+                secret: secret.clone(),
+            },
+        );
+
+        Ok(secret)
+    }
+
+    pub async fn url(&self, account: &str) -> Option<String> {
+        self.hooks.read().await.get(account).map(|hook| hook.url.clone())
+    }
+
+    pub async fn remove(&self, account: &str) -> bool {
+        self.hooks.write().await.remove(account).is_some()
+    }
+
+    /// Queues `payload` for the account's webhook, if it has one.
+    pub async fn notify(self: &Arc<Self>, payload: WebhookPayload) {
+        let url = match self.url(&payload.account).await {
+            Some(url) => url,
+            None => return,
+        };
+
+        let now = unix_time();
+        let mut log = self.log.lock().await;
+        let id = log.next_id;
+        log.next_id += 1;
+        log.deliveries.push_back(Delivery {
+            id,
+            url,
+            payload,
+            status: DeliveryStatus::Pending,
+            attempts: 0,
+            last_error: None,
+            created_at: now,
+            updated_at: now,
+        });
+        if log.deliveries.len() > LOG_SIZE {
+            log.deliveries.pop_front();
+        }
+
+        tokio::spawn(self.clone().deliver(id));
+    }
+
+    /// The account's deliveries, newest first.
+    pub async fn deliveries(&self, account: &str, status: Option<DeliveryStatus>) -> Vec<Delivery> {
+        self.log
+            .lock()
+            .await
+            .deliveries
+            .iter()
+            .rev()
+            .filter(|delivery| delivery.payload.account == account)
+            .filter(|delivery| status.is_none_or(|status| delivery.status == status))
+            .cloned()
+            .collect()
+    }
+
+    /// Sends failed deliveries of the account again, only `id` when given,
+    /// to the webhook's current url. Returns how many were queued.
+    pub async fn replay(self: &Arc<Self>, account: &str, id: Option<u64>) -> Result<usize, String> {
+        let url = self
+            .url(account)
+            .await
+            .ok_or(format!("account {} has no webhook", account))?;
+
+        let mut log = self.log.lock().await;
+        let replayed: Vec<u64> = log
+            .deliveries
+            .iter_mut()
+            .filter(|delivery| delivery.payload.account == account)
+            .filter(|delivery| id.is_none_or(|id| delivery.id == id))
+            .filter(|delivery| delivery.status == DeliveryStatus::Failed)
+            .map(|delivery| {
+                delivery.url = url.clone();
+                delivery.status = DeliveryStatus::Pending;
+                delivery.attempts = 0;
+                delivery.updated_at = unix_time();
+                delivery.id
+            })
+            .collect();
+
+        if let (Some(id), true) = (id, replayed.is_empty()) {
+            return Err(format!("no failed delivery {} for account {}", id, account));
+        }
+
+        for id in &replayed {
+            tokio::spawn(self.clone().deliver(*id));
+        }
+
+        Ok(replayed.len())
+    }
+
+    // posts a delivery until it is accepted or out of attempts
+    async fn deliver(self: Arc<Self>, id: u64) {
+        loop {
+            let (url, payload) = match self.log.lock().await.get_mut(id) {
+                Some(delivery) => (delivery.url.clone(), delivery.payload.clone()),
+                None => return, // dropped from the log
+            };
+
+            let result = self.post(&url, id, &payload).await;
+
+            let mut log = self.log.lock().await;
+            let delivery = match log.get_mut(id) {
+                Some(delivery) => delivery,
+                None => return,
+            };
+            delivery.attempts += 1;
+            delivery.updated_at = unix_time();
+
+            match result {
+                Ok(()) => {
+                    delivery.status = DeliveryStatus::Delivered;
+                    delivery.last_error = None;
+                    return;
+                }
+                Err(err) => {
+                    println!("webhook delivery {} to {} failed: {}", id, url, err);
+                    delivery.last_error = Some(err);
+                    if delivery.attempts >= MAX_ATTEMPTS {
+                        delivery.status = DeliveryStatus::Failed;
+                        return;
+                    }
+                }
+            }
+            let delay = retry_delay(delivery.attempts);
+            drop(log);
+
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    async fn post(&self, url: &str, id: u64, payload: &WebhookPayload) -> Result<(), String> {
+        let secret = match self.hooks.read().await.get(&payload.account) {
+            Some(hook) => hook.secret.clone(),
+            None => return Err("webhook was removed".to_string()),
+        };
+
+        let body = serde_json::to_vec(payload).map_err(|err| err.to_string())?;
+        let timestamp = unix_time().to_string();
+
+        let response = self
+            .http
+            .post(url)
+            .header("Content-Type", "application/json")
+            .header(EVENT_HEADER, payload.event)
+            .header(DELIVERY_HEADER, id.to_string())
+            .header(TIMESTAMP_HEADER, &timestamp)
+            .header(SIGNATURE_HEADER, sign(&secret, &timestamp, &body))
+            .body(body)
+            .send()
+            .await
+            .map_err(|err| err.to_string())?;
+
+        if !response.status().is_success() {
+            return Err(format!("webhook responded with {}", response.status()));
+        }
+
+        Ok(())
+    }
+}
+
+// pause after the `attempts`th failed attempt, doubling from the first
+fn retry_delay(attempts: u32) -> Duration {
+    INITIAL_RETRY_DELAY * 2u32.pow(attempts.saturating_sub(1))
+}
+
+fn sign(secret: &str, timestamp: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("hmac accepts keys of any length");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+
+    general_purpose::STANDARD.encode(mac.finalize().into_bytes())
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex as StdMutex;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+
+    fn payload(account: &str) -> WebhookPayload {
+        WebhookPayload {
+            event: NOTE_FINALIZED,
+            account: account.to_string(),
+            amount: 5,
+            token: "token".to_string(),
+            memo: String::new(),
+            tree_number: 0,
+            leaf_index: 3,
+            signature: "signature".to_string(),
+            slot: 42,
+            finalized: true,
+            block_time: None,
+        }
+    }
+
+    // a receiver answering 500 to its first request and 200 after, returns
+    // its url and the raw requests it got
+    async fn flaky_receiver() -> (String, Arc<StdMutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let requests = Arc::new(StdMutex::new(vec![]));
+
+        let received = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let request = read_request(&mut stream).await;
+
+                let status = {
+                    let mut received = received.lock().unwrap();
+                    received.push(request);
+                    if received.len() == 1 { "500 Internal Server Error" } else { "200 OK" }
+                };
+                let response = format!(
+                    "HTTP/1.1 {}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    status
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+                let _ = stream.shutdown().await;
+            }
+        });
+
+        (url, requests)
+    }
+
+    // reads the headers and the body they announce
+    async fn read_request(stream: &mut TcpStream) -> String {
+        let mut request = vec![];
+        let mut buffer = [0; 1024];
+        loop {
+            let read = stream.read(&mut buffer).await.unwrap_or(0);
+            if read == 0 {
+                break;
+            }
+            request.extend_from_slice(&buffer[..read]);
+
+            let text = String::from_utf8_lossy(&request);
+            if let Some(end) = text.find("\r\n\r\n") {
+                let length = header(&text[..end], "content-length")
+                    .and_then(|length| length.trim().parse::<usize>().ok())
+                    .unwrap_or(0);
+                if request.len() >= end + 4 + length {
+                    break;
+                }
+            }
+        }
+
+        String::from_utf8_lossy(&request).to_string()
+    }
+
+    fn header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+        request
+            .lines()
+            .filter_map(|line| line.split_once(": "))
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+
+    #[test]
+    fn signs_the_timestamp_and_body() {
+        let signature = general_purpose::STANDARD.decode(sign("secret", "100", b"{}")).unwrap();
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+        mac.update(b"100.{}");
+        assert!(mac.verify_slice(&signature).is_ok());
+
+        assert_ne!(sign("secret", "101", b"{}"), sign("secret", "100", b"{}"));
+        assert_ne!(sign("other", "100", b"{}"), sign("secret", "100", b"{}"));
+    }
+
+    #[test]
+    fn retry_delays_double() {
+        let delays: Vec<u64> = (1..MAX_ATTEMPTS)
+            .map(|attempts| retry_delay(attempts).as_secs())
+            .collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 16]);
+    }
+
+    #[tokio::test]
+    async fn retries_signed_deliveries_until_accepted() {
+        let webhooks = Arc::new(Webhooks::new());
+        let (url, requests) = flaky_receiver().await;
+        let secret = webhooks.set("alice", &url).await.unwrap();
+
+        webhooks.notify(payload("alice")).await;
+        // nobody listens for bob
+        webhooks.notify(payload("bob")).await;
+
+        let mut delivered = vec![];
+        for _ in 0..50 {
+            delivered = webhooks.deliveries("alice", Some(DeliveryStatus::Delivered)).await;
+            if !delivered.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(delivered.len(), 1);
+        assert_eq!(delivered[0].attempts, 2);
+        assert!(webhooks.deliveries("bob", None).await.is_empty());
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        let request = &requests[1];
+        let (_, body) = request.split_once("\r\n\r\n").unwrap();
+        let timestamp = header(request, TIMESTAMP_HEADER).unwrap();
+        assert_eq!(header(request, EVENT_HEADER), Some(NOTE_FINALIZED));
+        assert_eq!(
+            header(request, SIGNATURE_HEADER),
+            Some(sign(&secret, timestamp, body.as_bytes()).as_str())
+        );
+    }
+}
@@ -0,0 +1,196 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use base64::{engine::general_purpose, Engine as _};
+use darksol::{DepositEvent, NullifierEvent, PreCommitments, ShieldCipherText};
+use solana_sdk::pubkey::Pubkey;
+use tokio::sync::{RwLock, mpsc};
+use veil_types::{MerkleTreeSparse, UTXO, generate_nullifier};
+
+use indexer::{
+    account::AccountKeys,
+    client::{
+        DEPOSIT_EVENT, NULLIFIERS_EVENT, NoteKey, NoteKind, TransactionLogs,
+        logs::parse_program_events,
+        source::{EventSource, FileSource, Recorder},
+    },
+    event::IndexerEvent,
+    metrics::Metrics,
+    processor::{Pipeline, TxContext, store::StoreProcessor},
+    storage::db::memdb::MemDb,
+};
+
+const TREE_NUMBER: u64 = 0;
+
+fn random_bytes() -> Vec<u8> {
+    (0..32).map(|_| rand::random()).collect()
+}
+
+// the logs the program prints for one instruction emitting `events`
+fn instruction_logs(
+    program_id: &Pubkey,
+    events: &[(&str, Vec<u8>)],
+    failed: bool,
+) -> Vec<String> {
+    let encode = |data: &[u8]| general_purpose::STANDARD.encode(data);
+
+    let mut logs = vec![format!("Program {} invoke [1]", program_id)];
+    for (name, data) in events {
+        logs.push(format!("Program data: {} {}", encode(name.as_bytes()), encode(data)));
+    }
+    logs.push(match failed {
+        true => format!("Program {} failed: custom program error: 0x1", program_id),
+        false => format!("Program {} success", program_id),
+    });
+    logs
+}
+
+// a deposit of `amount` to `keys` landing at `start_position`, with its leaf
+fn deposit(keys: &AccountKeys, amount: u64, start_position: u64) -> (Vec<u8>, Vec<u8>) {
+    let token = vec![1; 32];
+    let utxo = UTXO::new(
+        keys.spending_key.clone(),
+        keys.viewing_key.clone(),
+        token.clone(),
+        random_bytes(),
+        random_bytes(),
+        amount,
+        format!("deposit {}", amount),
+    );
+    let pre_commitments = PreCommitments::new(amount, token, utxo.utxo_public_key());
+    let cipher = utxo.encrypt_for_deposit(keys.viewing_key.clone(), random_bytes());
+    let leaf = pre_commitments.hash();
+
+    let event = DepositEvent {
+        pre_commitments,
+        shield_cipher_text: ShieldCipherText::new(cipher.shield_key, cipher.cipher, utxo.nonce()),
+        tree_number: TREE_NUMBER,
+        start_position,
+    };
+
+    (borsh::to_vec(&event).unwrap(), leaf)
+}
+
+fn transaction(signature: &str, slot: u64, logs: Vec<String>) -> TransactionLogs {
+    TransactionLogs {
+        signature: signature.to_string(),
+        slot,
+        logs,
+        finalized: true,
+        block_time: Some(1_700_000_000 + slot as i64),
+    }
+}
+
+// records `transactions` the way a running indexer does, then reads them
+// back through the replay source
+async fn record_and_replay(
+    path: &PathBuf,
+    transactions: &[TransactionLogs],
+) -> Vec<TransactionLogs> {
+    let _ = std::fs::remove_file(path);
+    let recorder = Recorder::open(path.clone()).unwrap();
+    for tx_logs in transactions {
+        recorder.record(tx_logs).unwrap();
+    }
+
+    let source = FileSource::new(path.clone(), Arc::new(Metrics::new().unwrap()));
+    let (tx, mut rx) = mpsc::channel(transactions.len().max(1));
+    source.backfill(tx, None).await.unwrap();
+
+    let mut replayed = vec![];
+    while let Some(tx_logs) = rx.recv().await {
+        replayed.push(tx_logs);
+    }
+    replayed
+}
+
+#[tokio::test]
+async fn replays_a_recording_into_the_store() {
+    let program_id = Pubkey::new_unique();
+    let alice = AccountKeys::new(random_bytes(), random_bytes()).unwrap();
+    let bob = AccountKeys::new(random_bytes(), random_bytes()).unwrap();
+
+    let (first, first_leaf) = deposit(&alice, 10, 0);
+    let (second, second_leaf) = deposit(&alice, 20, 1);
+    let (third, third_leaf) = deposit(&alice, 30, 2);
+    let (reverted, _) = deposit(&alice, 40, 3);
+    let spend = borsh::to_vec(&NullifierEvent {
+        nullifiers: vec![generate_nullifier(alice.viewing_key.clone(), 0)],
+    })
+    .unwrap();
+
+    let mut two_deposits = instruction_logs(&program_id, &[(DEPOSIT_EVENT, second)], false);
+    two_deposits.extend(instruction_logs(&program_id, &[(DEPOSIT_EVENT, third)], false));
+    let transactions = vec![
+        transaction("first", 100, instruction_logs(&program_id, &[(DEPOSIT_EVENT, first)], false)),
+        transaction("second", 101, two_deposits),
+        transaction(
+            "reverted",
+            102,
+            instruction_logs(&program_id, &[(DEPOSIT_EVENT, reverted)], true),
+        ),
+        transaction(
+            "spend",
+            103,
+            instruction_logs(&program_id, &[(NULLIFIERS_EVENT, spend)], false),
+        ),
+    ];
+
+    let path = std::env::temp_dir().join(format!("indexer-replay-{}.jsonl", std::process::id()));
+    let replayed = record_and_replay(&path, &transactions).await;
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(replayed.len(), transactions.len());
+
+    let memdb = Arc::new(RwLock::new(MemDb::new()));
+    let (refetch_tx, _refetch_rx) = mpsc::channel(1);
+    let pipeline = Pipeline::new().with(StoreProcessor::new(memdb.clone(), refetch_tx));
+    let account_keys = Arc::new(vec![("alice".to_string(), alice), ("bob".to_string(), bob)]);
+
+    for tx_logs in &replayed {
+        let parsed = parse_program_events(&program_id, tx_logs);
+        assert!(parsed.rejected.is_empty());
+        if parsed.failed {
+            continue;
+        }
+
+        let events: Vec<IndexerEvent> = parsed
+            .events
+            .iter()
+            .map(|event| IndexerEvent::decode(event, &tx_logs.tx_ref()).unwrap())
+            .collect();
+        let context = TxContext::new(account_keys.clone(), &events);
+        for event in &events {
+            assert!(!pipeline.process(event, &context).await.deferred);
+        }
+    }
+
+    let db = memdb.read().await;
+
+    // the reverted deposit left no leaf behind
+    let leaves = vec![first_leaf, second_leaf, third_leaf];
+    let mut expected = MerkleTreeSparse::<32>::new(TREE_NUMBER);
+    expected.insert(leaves.clone());
+    let tree = db.tree(TREE_NUMBER).unwrap();
+    assert_eq!(tree.next_leaf_index(), 3);
+    assert_eq!(tree.leaves(0, 10), leaves);
+    assert_eq!(tree.root(), expected.root());
+
+    let notes = db.notes("alice").unwrap();
+    let amounts: Vec<(u64, u64)> = notes
+        .iter()
+        .map(|(key, note)| (key.leaf_index, note.utxo.amount()))
+        .collect();
+    assert_eq!(amounts, vec![(0, 10), (1, 20), (2, 30)]);
+    assert!(notes.values().all(|note| note.kind == NoteKind::Deposit));
+    assert!(db.notes("bob").is_none_or(|notes| notes.is_empty()));
+
+    let spent_key = NoteKey { tree_number: TREE_NUMBER, leaf_index: 0 };
+    let spent_by = db.note("alice", &spent_key).and_then(|note| note.spent.as_ref());
+    assert_eq!(spent_by.map(|tx| tx.signature.as_str()), Some("spend"));
+    assert!(
+        notes
+            .iter()
+            .filter(|(key, _)| **key != spent_key)
+            .all(|(_, note)| note.spent.is_none())
+    );
+}
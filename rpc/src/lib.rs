@@ -0,0 +1,175 @@
+use std::time::Duration;
+
+use solana_client::{nonblocking::rpc_client::RpcClient, rpc_client::RpcClientConfig};
+use solana_sdk::commitment_config::CommitmentConfig;
+
+pub mod limiter;
+pub mod retry;
+pub mod sender;
+
+pub use sender::ResilientSender;
+
+/// How requests are spread over the endpoints and retried.
+#[derive(Clone, Debug)]
+pub struct RpcConfig {
+    // tried in order, later ones only when the one in use keeps failing
+    pub endpoints: Vec<String>,
+    // per endpoint, unlimited when unset
+    pub requests_per_second: Option<u32>,
+    // retries after the first attempt, across all endpoints
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub timeout: Duration,
+}
+
+impl RpcConfig {
+    pub fn new(endpoints: Vec<String>) -> Self {
+        RpcConfig {
+            endpoints,
+            requests_per_second: None,
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    pub fn with_rate_limit(mut self, requests_per_second: Option<u32>) -> Self {
+        self.requests_per_second = requests_per_second;
+        self
+    }
+
+    /// Overrides the number of retries, the default is kept when unset.
+    pub fn with_max_retries(mut self, max_retries: Option<u32>) -> Self {
+        if let Some(max_retries) = max_retries {
+            self.max_retries = max_retries;
+        }
+        self
+    }
+
+    /// Overrides the first and the longest pause between retries, each
+    /// default is kept when unset.
+    pub fn with_backoff(mut self, initial: Option<Duration>, max: Option<Duration>) -> Self {
+        if let Some(initial) = initial {
+            self.initial_backoff = initial;
+        }
+        if let Some(max) = max {
+            self.max_backoff = max;
+        }
+        self
+    }
+}
+
+/// An `RpcClient` whose requests go through a `ResilientSender`, so every
+/// call gets rate limiting, retries and failover.
+pub fn new_client(config: &RpcConfig, commitment: CommitmentConfig) -> Result<RpcClient, String> {
+    let sender = ResilientSender::from_config(config)?;
+
+    Ok(RpcClient::new_sender(
+        sender,
+        RpcClientConfig::with_commitment(commitment),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+
+    // answers every request with `status` and `body` over plain http,
+    // returns its url and the number of requests it served
+    async fn serve(status: &'static str, body: &'static str) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let served = Arc::new(AtomicUsize::new(0));
+
+        let counter = served.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                read_request(&mut stream).await;
+                counter.fetch_add(1, Ordering::Relaxed);
+                let response = format!(
+                    "HTTP/1.1 {}\r\ncontent-type: application/json\r\n\
+                     content-length: {}\r\nconnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+                let _ = stream.shutdown().await;
+            }
+        });
+
+        (url, served)
+    }
+
+    // reads the headers and the body they announce
+    async fn read_request(stream: &mut TcpStream) {
+        let mut request = vec![];
+        let mut buffer = [0; 1024];
+        loop {
+            let read = stream.read(&mut buffer).await.unwrap_or(0);
+            if read == 0 {
+                return;
+            }
+            request.extend_from_slice(&buffer[..read]);
+
+            let text = String::from_utf8_lossy(&request);
+            if let Some(end) = text.find("\r\n\r\n") {
+                let length = text[..end]
+                    .lines()
+                    .filter_map(|line| line.split_once(':'))
+                    .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                    .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+                    .unwrap_or(0);
+                if request.len() >= end + 4 + length {
+                    return;
+                }
+            }
+        }
+    }
+
+    fn config(endpoints: Vec<String>) -> RpcConfig {
+        RpcConfig::new(endpoints)
+            .with_max_retries(Some(2))
+            .with_backoff(Some(Duration::from_millis(10)), Some(Duration::from_millis(10)))
+    }
+
+    #[tokio::test]
+    async fn fails_over_between_http_endpoints() {
+        let (down, down_served) = serve("503 Service Unavailable", "").await;
+        let (up, up_served) = serve("200 OK", r#"{"jsonrpc":"2.0","result":42,"id":1}"#).await;
+        let client = new_client(&config(vec![down, up]), CommitmentConfig::confirmed()).unwrap();
+
+        assert_eq!(client.get_slot().await.unwrap(), 42);
+        assert!(down_served.load(Ordering::Relaxed) >= 1);
+        assert!(up_served.load(Ordering::Relaxed) >= 1);
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_requests_an_http_endpoint_rejects() {
+        let (rejecting, _) = serve(
+            "200 OK",
+            r#"{"jsonrpc":"2.0","error":{"code":-32602,"message":"invalid params"},"id":1}"#,
+        )
+        .await;
+        let (spare, spare_served) =
+            serve("200 OK", r#"{"jsonrpc":"2.0","result":42,"id":1}"#).await;
+        let client =
+            new_client(&config(vec![rejecting, spare]), CommitmentConfig::confirmed()).unwrap();
+
+        assert!(client.get_slot().await.is_err());
+        // sending it elsewhere would not have helped
+        assert_eq!(spare_served.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,111 @@
+use std::time::Duration;
+
+use solana_client::{
+    client_error::{ClientError, ClientErrorKind},
+    rpc_request::RpcError,
+};
+
+// json-rpc error codes a node returns while it cannot serve the request yet
+// or at all, another node or a later attempt may succeed
+const NODE_UNHEALTHY: i64 = -32005;
+const BLOCK_NOT_AVAILABLE: i64 = -32004;
+const TRANSACTION_HISTORY_NOT_AVAILABLE: i64 = -32011;
+const BLOCK_STATUS_NOT_AVAILABLE_YET: i64 = -32014;
+const MIN_CONTEXT_SLOT_NOT_REACHED: i64 = -32016;
+const INTERNAL_ERROR: i64 = -32603;
+// some providers report rate limits in the response body
+const TOO_MANY_REQUESTS: i64 = 429;
+
+/// What to do about a failed request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Retry {
+    /// The request itself is wrong, sending it again changes nothing.
+    Never,
+    /// The endpoint is unreachable or unable to answer, try the next one.
+    Failover,
+    /// The endpoint asked us to slow down.
+    RateLimited,
+}
+
+pub fn classify(err: &ClientError) -> Retry {
+    match err.kind() {
+        ClientErrorKind::Io(_) => Retry::Failover,
+        ClientErrorKind::Reqwest(err) => match err.status() {
+            Some(status) if status.as_u16() == TOO_MANY_REQUESTS as u16 => Retry::RateLimited,
+            Some(status) if status.is_server_error() => Retry::Failover,
+            Some(_) => Retry::Never,
+            // no response at all: timeouts, refused connections, resets
+            None => Retry::Failover,
+        },
+        ClientErrorKind::RpcError(RpcError::RpcResponseError { code, .. }) => match *code {
+            TOO_MANY_REQUESTS => Retry::RateLimited,
+            NODE_UNHEALTHY
+            | BLOCK_NOT_AVAILABLE
+            | TRANSACTION_HISTORY_NOT_AVAILABLE
+            | BLOCK_STATUS_NOT_AVAILABLE_YET
+            | MIN_CONTEXT_SLOT_NOT_REACHED
+            | INTERNAL_ERROR => Retry::Failover,
+            _ => Retry::Never,
+        },
+        ClientErrorKind::RpcError(RpcError::RpcRequestError(_)) => Retry::Failover,
+        // a garbled response says more about the endpoint than the request
+        ClientErrorKind::SerdeJson(_) => Retry::Failover,
+        _ => Retry::Never,
+    }
+}
+
+/// Delay before the `round`th retry, doubling from `initial` up to `max`.
+pub fn backoff(round: u32, initial: Duration, max: Duration) -> Duration {
+    initial
+        .checked_mul(1 << round.min(16))
+        .map_or(max, |delay| delay.min(max))
+}
+
+#[cfg(test)]
+mod tests {
+    use solana_client::rpc_request::RpcResponseErrorData;
+
+    use super::*;
+
+    fn rpc_error(code: i64) -> ClientError {
+        ClientErrorKind::RpcError(RpcError::RpcResponseError {
+            code,
+            message: "mock".to_string(),
+            data: RpcResponseErrorData::Empty,
+        })
+        .into()
+    }
+
+    #[test]
+    fn classifies_rpc_errors() {
+        assert_eq!(classify(&rpc_error(TOO_MANY_REQUESTS)), Retry::RateLimited);
+        assert_eq!(classify(&rpc_error(NODE_UNHEALTHY)), Retry::Failover);
+        assert_eq!(classify(&rpc_error(MIN_CONTEXT_SLOT_NOT_REACHED)), Retry::Failover);
+        // invalid params
+        assert_eq!(classify(&rpc_error(-32602)), Retry::Never);
+    }
+
+    #[test]
+    fn classifies_transport_errors() {
+        let io = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        assert_eq!(classify(&io.into()), Retry::Failover);
+
+        let request = ClientErrorKind::RpcError(RpcError::RpcRequestError("mock".to_string()));
+        assert_eq!(classify(&request.into()), Retry::Failover);
+
+        let custom = ClientErrorKind::Custom("mock".to_string());
+        assert_eq!(classify(&custom.into()), Retry::Never);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let initial = Duration::from_millis(100);
+        let max = Duration::from_secs(1);
+
+        let delays: Vec<u128> = (0..6)
+            .map(|round| backoff(round, initial, max).as_millis())
+            .collect();
+        assert_eq!(delays, vec![100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(backoff(u32::MAX, initial, max), max);
+    }
+}
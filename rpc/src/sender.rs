@@ -0,0 +1,298 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde_json::Value;
+use solana_client::{
+    client_error::Result as ClientResult,
+    http_sender::HttpSender,
+    rpc_request::RpcRequest,
+    rpc_sender::{RpcSender, RpcTransportStats},
+};
+
+use crate::{
+    RpcConfig,
+    limiter::RateLimiter,
+    retry::{Retry, backoff, classify},
+};
+
+struct Endpoint {
+    sender: Box<dyn RpcSender + Send + Sync>,
+    limiter: Option<RateLimiter>,
+}
+
+/// Sends each request to the endpoint in use, moving on to the next one when
+/// it fails in a way another attempt could fix. Plugged into an `RpcClient`
+/// it makes every call resilient without changing the call sites.
+pub struct ResilientSender {
+    endpoints: Vec<Endpoint>,
+    // index of the endpoint requests go to, moves on failover
+    current: AtomicUsize,
+    max_retries: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl ResilientSender {
+    /// One http endpoint per configured url.
+    pub fn from_config(config: &RpcConfig) -> Result<Self, String> {
+        let senders = config
+            .endpoints
+            .iter()
+            .map(|url| {
+                Box::new(HttpSender::new_with_timeout(url.clone(), config.timeout))
+                    as Box<dyn RpcSender + Send + Sync>
+            })
+            .collect();
+
+        ResilientSender::new(senders, config)
+    }
+
+    /// Wraps arbitrary senders, such as ones talking to a mock server. The
+    /// urls of `config` are ignored.
+    pub fn new(
+        senders: Vec<Box<dyn RpcSender + Send + Sync>>,
+        config: &RpcConfig,
+    ) -> Result<Self, String> {
+        if senders.is_empty() {
+            return Err("no rpc endpoint configured".to_string());
+        }
+
+        let endpoints = senders
+            .into_iter()
+            .map(|sender| Endpoint {
+                sender,
+                limiter: config.requests_per_second.map(RateLimiter::new),
+            })
+            .collect();
+
+        Ok(ResilientSender {
+            endpoints,
+            current: AtomicUsize::new(0),
+            max_retries: config.max_retries,
+            initial_backoff: config.initial_backoff,
+            max_backoff: config.max_backoff,
+        })
+    }
+
+    fn current(&self) -> usize {
+        self.current.load(Ordering::Relaxed) % self.endpoints.len()
+    }
+
+    // moves off `failed` unless a concurrent request did already
+    fn fail_over(&self, failed: usize) {
+        let next = (failed + 1) % self.endpoints.len();
+        let _ = self
+            .current
+            .compare_exchange(failed, next, Ordering::Relaxed, Ordering::Relaxed);
+    }
+}
+
+#[async_trait]
+impl RpcSender for ResilientSender {
+    async fn send(&self, request: RpcRequest, params: Value) -> ClientResult<Value> {
+        let mut retries = 0;
+
+        loop {
+            let index = self.current();
+            let endpoint = &self.endpoints[index];
+            if let Some(limiter) = &endpoint.limiter {
+                limiter.acquire().await;
+            }
+
+            let err = match endpoint.sender.send(request, params.clone()).await {
+                Ok(response) => return Ok(response),
+                Err(err) => err,
+            };
+
+            let retry = classify(&err);
+            if retry == Retry::Never || retries >= self.max_retries {
+                return Err(err);
+            }
+            retries += 1;
+
+            println!(
+                "{} failed on {}, retrying ({}/{}): {}",
+                request,
+                endpoint.sender.url(),
+                retries,
+                self.max_retries,
+                err
+            );
+            self.fail_over(index);
+
+            // switching endpoints is free until every one of them failed in
+            // this round, a rate limit always calls for a pause
+            let round = (retries - 1) / self.endpoints.len() as u32;
+            let full_round = retries % self.endpoints.len() as u32 == 0;
+            if retry == Retry::RateLimited || full_round {
+                let delay = backoff(round, self.initial_backoff, self.max_backoff);
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+
+    fn get_transport_stats(&self) -> RpcTransportStats {
+        self.endpoints[self.current()].sender.get_transport_stats()
+    }
+
+    fn url(&self) -> String {
+        self.endpoints[self.current()].sender.url()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use solana_client::{
+        client_error::{ClientError, ClientErrorKind},
+        rpc_request::{RpcError, RpcResponseErrorData},
+    };
+    use tokio::time::Instant;
+
+    use super::*;
+
+    type Response = fn() -> ClientResult<Value>;
+    // endpoint and time of every request sent
+    type Calls = Arc<Mutex<Vec<(usize, Instant)>>>;
+
+    // answers with `responses` in order, repeating the last one
+    struct MockSender {
+        id: usize,
+        responses: Vec<Response>,
+        sent: AtomicUsize,
+        calls: Calls,
+    }
+
+    #[async_trait]
+    impl RpcSender for MockSender {
+        async fn send(&self, _request: RpcRequest, _params: Value) -> ClientResult<Value> {
+            self.calls.lock().unwrap().push((self.id, Instant::now()));
+            let sent = self.sent.fetch_add(1, Ordering::Relaxed);
+            self.responses[sent.min(self.responses.len() - 1)]()
+        }
+
+        fn get_transport_stats(&self) -> RpcTransportStats {
+            RpcTransportStats::default()
+        }
+
+        fn url(&self) -> String {
+            format!("mock-{}", self.id)
+        }
+    }
+
+    fn ok() -> ClientResult<Value> {
+        Ok(Value::from(42))
+    }
+
+    fn rpc_error(code: i64) -> ClientError {
+        ClientErrorKind::RpcError(RpcError::RpcResponseError {
+            code,
+            message: "mock".to_string(),
+            data: RpcResponseErrorData::Empty,
+        })
+        .into()
+    }
+
+    fn unhealthy() -> ClientResult<Value> {
+        Err(rpc_error(-32005))
+    }
+
+    fn rate_limited() -> ClientResult<Value> {
+        Err(rpc_error(429))
+    }
+
+    fn invalid_params() -> ClientResult<Value> {
+        Err(rpc_error(-32602))
+    }
+
+    fn reset() -> ClientResult<Value> {
+        Err(std::io::Error::from(std::io::ErrorKind::ConnectionReset).into())
+    }
+
+    fn sender(endpoints: Vec<Vec<Response>>, calls: &Calls) -> ResilientSender {
+        let config = RpcConfig::new(vec![])
+            .with_max_retries(Some(4))
+            .with_backoff(Some(Duration::from_millis(100)), Some(Duration::from_secs(1)));
+        let senders = endpoints
+            .into_iter()
+            .enumerate()
+            .map(|(id, responses)| {
+                Box::new(MockSender {
+                    id,
+                    responses,
+                    sent: AtomicUsize::new(0),
+                    calls: calls.clone(),
+                }) as Box<dyn RpcSender + Send + Sync>
+            })
+            .collect();
+
+        ResilientSender::new(senders, &config).unwrap()
+    }
+
+    // endpoint of each call with its offset from `start` in milliseconds
+    fn sent(calls: &Calls, start: Instant) -> Vec<(usize, u128)> {
+        calls
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, at)| (*id, (*at - start).as_millis()))
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fails_over_without_waiting() {
+        let calls = Calls::default();
+        let sender = sender(vec![vec![unhealthy], vec![ok]], &calls);
+        let start = Instant::now();
+
+        let response = sender.send(RpcRequest::GetSlot, Value::Null).await.unwrap();
+
+        assert_eq!(response, Value::from(42));
+        assert_eq!(sent(&calls, start), vec![(0, 0), (1, 0)]);
+        // later requests stay on the endpoint that answered
+        assert_eq!(sender.url(), "mock-1");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backs_off_after_every_endpoint_failed() {
+        let calls = Calls::default();
+        let sender = sender(vec![vec![reset], vec![reset]], &calls);
+        let start = Instant::now();
+
+        let result = sender.send(RpcRequest::GetSlot, Value::Null).await;
+
+        assert!(result.is_err());
+        // the first attempt and 4 retries, pausing 100ms then 200ms after
+        // each full round
+        assert_eq!(
+            sent(&calls, start),
+            vec![(0, 0), (1, 0), (0, 100), (1, 100), (0, 300)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_when_rate_limited() {
+        let calls = Calls::default();
+        let sender = sender(vec![vec![rate_limited, ok]], &calls);
+        let start = Instant::now();
+
+        let response = sender.send(RpcRequest::GetSlot, Value::Null).await;
+
+        assert!(response.is_ok());
+        assert_eq!(sent(&calls, start), vec![(0, 0), (0, 100)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_on_invalid_requests() {
+        let calls = Calls::default();
+        let sender = sender(vec![vec![invalid_params], vec![ok]], &calls);
+        let start = Instant::now();
+
+        let result = sender.send(RpcRequest::GetSlot, Value::Null).await;
+
+        assert!(result.is_err());
+        assert_eq!(sent(&calls, start), vec![(0, 0)]);
+    }
+}